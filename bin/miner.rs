@@ -1,14 +1,8 @@
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
-
-#[derive(Deserialize)]
-struct Tip {
-    index: u64,
-    hash: String,
-}
+use weave_node::blockchain::{Block, BlockTemplate};
+use weave_node::genesis::GenesisSpec;
+use weave_node::mining::solve;
 
 #[tokio::main]
 async fn main() {
@@ -18,9 +12,11 @@ async fn main() {
         .build()
         .unwrap();
 
-    // 1. Fetch latest tip block
-    let tip: Tip = client
-        .get(format!("{}/tip", node_url))
+    // 1. Fetch a template for the next block, paying the reward to us
+    let miner_address = std::env::var("MINER_ADDRESS").unwrap_or_default();
+    let template: BlockTemplate = client
+        .get(format!("{}/template", node_url))
+        .query(&[("address", &miner_address)])
         .send()
         .await
         .unwrap()
@@ -28,33 +24,33 @@ async fn main() {
         .await
         .unwrap();
 
-    // 2. Mine manually if API unavailable or rate-limited
-    let index = tip.index + 1;
-    let prev_hash = tip.hash;
-    let data = "hello from miner";
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-    let mut nonce = 0u64;
-    let block_hash = loop {
-        let input = format!("{}{}{}{}{}", index, timestamp, prev_hash, data, nonce);
-        let hash = format!("{:x}", Sha256::digest(input.as_bytes()));
-        if hash.starts_with("0000") {
-            break hash;
-        }
-        nonce += 1;
-    };
+    // 2. Grind a nonce with the network's algorithm on every core
+    let pow = GenesisSpec::from_env().unwrap().chain_params().pow;
+    if pow.config() != template.pow {
+        println!("❌ Node mines with {:?}, our genesis spec says {:?}", template.pow, pow.config());
+        return;
+    }
+    let mut header = template.header;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    header.timestamp = now.clamp(template.min_timestamp, template.max_timestamp);
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let header = tokio::task::spawn_blocking(move || solve(&header, &*pow, threads, || false))
+        .await
+        .unwrap()
+        .expect("never cancelled");
+    let block = Block::assemble(header, template.transactions);
 
-    println!("🧱 Mined block with hash: {}", block_hash);
+    println!("🧱 Mined block with hash: {}", block.hash);
 
-    // 3. Try POST /mine if access key available
+    // 3. Hand the solved block to the node
     let res = client
-        .post(format!("{}/mine", node_url))
-        .header("x-api-key", "secretkey")
-        .json(&data)
+        .post(format!("{}/submit_block", node_url))
+        .json(&block)
         .send()
         .await;
 
     match res {
-        Ok(r) => println!("✅ Submitted via /mine: {}", r.status()),
-        Err(e) => println!("❌ Failed to submit mined block via /mine: {}", e),
+        Ok(r) => println!("✅ Submitted via /submit_block: {}", r.status()),
+        Err(e) => println!("❌ Failed to submit mined block: {}", e),
     }
 }
@@ -1,94 +1,1075 @@
-// === blockchain.rs ===
-
-use crate::cryptography::{calculate_hash, verify_pow};
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Block {
-    pub index: u64,
-    pub timestamp: u128,
-    pub prev_hash: String,
-    pub hash: String,
-    pub data: String,
-    pub nonce: u64,
-}
-
-impl Block {
-    pub fn new(index: u64, timestamp: u128, prev_hash: String, data: String, nonce: u64) -> Self {
-        let hash = calculate_hash(index, timestamp, &prev_hash, &data, nonce);
-        Block {
-            index,
-            timestamp,
-            prev_hash,
-            hash,
-            data,
-            nonce,
-        }
-    }
-
-    pub fn new_dummy() -> Self {
-        Block::new(0, 0, "0".into(), "GENESIS".into(), 0)
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Blockchain {
-    pub blocks: Vec<Block>,
-}
-
-impl Blockchain {
-    pub fn new() -> Self {
-        Blockchain {
-            blocks: vec![Block::new_dummy()],
-        }
-    }
-
-    pub fn tip(&self) -> &Block {
-        self.blocks.last().expect("Chain should have at least genesis")
-    }
-
-    pub fn add_block(&mut self, block: Block) -> bool {
-        let tip = self.tip();
-        if block.prev_hash != tip.hash {
-            println!("❌ Rejected block: prev_hash mismatch");
-            return false;
-        }
-        if !verify_pow(&block.hash) {
-            println!("❌ Rejected block: PoW invalid");
-            return false;
-        }
-        self.blocks.push(block);
-        true
-    }
-
-    pub fn mine_block(&mut self, data: String) -> Block {
-        let tip = self.tip();
-        let index = tip.index + 1;
-        let timestamp = chrono::Utc::now().timestamp_millis() as u128;
-        let prev_hash = tip.hash.clone();
-        let mut nonce = 0;
-        loop {
-            let hash = calculate_hash(index, timestamp, &prev_hash, &data, nonce);
-            if verify_pow(&hash) {
-                return Block {
-                    index,
-                    timestamp,
-                    prev_hash,
-                    hash,
-                    data,
-                    nonce,
-                };
-            }
-            nonce += 1;
-        }
-    }
-
-    pub fn sync(&mut self, other: Blockchain) -> bool {
-        if other.blocks.len() > self.blocks.len() {
-            self.blocks = other.blocks;
-            true
-        } else {
-            false
-        }
-    }
-}
+// === blockchain.rs ===
+
+use crate::checkpoints::Checkpoints;
+use crate::cryptography::calculate_hash;
+use crate::difficulty::{block_work, compact_to_target, next_bits, MAX_RETARGET_FACTOR};
+use crate::encoding::{legacy_header_version, HEADER_VERSION};
+use crate::genesis::{ConsensusSpec, GenesisSpec};
+use crate::hash::Hash256;
+use crate::merkle::merkle_root;
+use crate::mempool::{Mempool, MempoolError, MAX_BLOCK_TRANSACTIONS};
+use crate::mining::solve;
+use crate::orphans::OrphanPool;
+use crate::params::ChainParams;
+use crate::pow::PowConfig;
+use crate::state::{Account, State};
+use crate::transaction::Transaction;
+use crate::validation::{check_block, check_hash, check_header, median_time_past, validate_blocks, BlockError, ChainError};
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The part of a block that is hashed and mined. It commits to the body via `merkle_root`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    /// Layout of the header's hashed encoding; see `encoding::encode_header`.
+    #[serde(default = "legacy_header_version")]
+    pub version: u32,
+    pub index: u64,
+    pub timestamp: u128,
+    pub prev_hash: Hash256,
+    pub merkle_root: Hash256,
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub hash: Hash256,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn new(index: u64, timestamp: u128, prev_hash: Hash256, transactions: Vec<Transaction>, bits: u32, nonce: u64) -> Self {
+        let header = BlockHeader {
+            version: HEADER_VERSION,
+            index,
+            timestamp,
+            prev_hash,
+            merkle_root: Block::compute_merkle_root(&transactions),
+            bits,
+            nonce,
+        };
+        let hash = calculate_hash(&header);
+        Block {
+            header,
+            hash,
+            transactions,
+        }
+    }
+
+    /// Ids of the block's transactions, in order; these are the merkle leaves.
+    pub fn tx_ids(&self) -> Vec<Hash256> {
+        self.transactions.iter().map(|tx| tx.id()).collect()
+    }
+
+    pub fn compute_merkle_root(transactions: &[Transaction]) -> Hash256 {
+        merkle_root(&transactions.iter().map(|tx| tx.id()).collect::<Vec<_>>())
+    }
+
+    /// Pairs a solved header with the body it commits to.
+    pub fn assemble(header: BlockHeader, transactions: Vec<Transaction>) -> Self {
+        Block {
+            hash: calculate_hash(&header),
+            header,
+            transactions,
+        }
+    }
+}
+
+/// An unsolved block: a header with nonce zero and the body it commits to,
+/// plus what a miner needs to know to solve it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockTemplate {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+    /// Valid timestamps for the header, inclusive; see `validation::check_timestamp`.
+    pub min_timestamp: u128,
+    pub max_timestamp: u128,
+    /// The PoW hash must not exceed this, read as a big-endian number.
+    pub target: Hash256,
+    /// The network's proof-of-work algorithm and its parameters.
+    pub pow: PowConfig,
+}
+
+/// Blocks removed from and added to the canonical chain by a reorg, in chain order.
+#[derive(Debug, Clone, Default)]
+pub struct Reorg {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>,
+}
+
+/// Where an accepted block ended up.
+#[derive(Debug, Clone)]
+pub enum BlockStatus {
+    /// The block extended the canonical tip.
+    Extended,
+    /// The block was stored on a branch with less work than the canonical chain.
+    SideBranch,
+    /// The block's branch overtook the canonical chain, which switched to it.
+    Reorg(Reorg),
+    /// The block's parent is unknown, so it was held in the orphan pool.
+    /// `missing` is the oldest ancestor that still has to be fetched.
+    Orphan { missing: Hash256 },
+}
+
+/// A block known to the node together with the total work of the branch ending at it.
+#[derive(Debug, Clone)]
+struct TreeEntry {
+    block: Block,
+    work: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Blockchain {
+    /// The canonical chain, oldest retained block first.
+    pub blocks: Vec<Block>,
+    /// Hash of the network's genesis block, kept so a pruned chain still
+    /// knows which network it belongs to.
+    #[serde(default)]
+    pub genesis_hash: Hash256,
+    /// Not serialized: the genesis spec supplies them on load.
+    #[serde(skip)]
+    pub params: ChainParams,
+    /// Blocks the canonical chain must contain; no reorg may undo them.
+    #[serde(skip)]
+    pub checkpoints: Checkpoints,
+    /// Account state as of the canonical tip.
+    #[serde(default)]
+    pub state: State,
+    /// Transactions waiting to be mined on top of the canonical tip.
+    #[serde(skip)]
+    pub mempool: Mempool,
+    /// Blocks received before their parents.
+    #[serde(skip)]
+    pub orphans: OrphanPool,
+    /// Every known block, canonical or not, keyed by hash.
+    #[serde(skip)]
+    tree: HashMap<Hash256, TreeEntry>,
+    /// Canonical block hash at each retained height.
+    #[serde(skip)]
+    by_height: HashMap<u64, Hash256>,
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Blockchain::new()
+    }
+}
+
+impl Blockchain {
+    /// Starts a chain on the built-in devnet genesis.
+    pub fn new() -> Self {
+        Blockchain::from_genesis(&GenesisSpec::default())
+    }
+
+    pub fn from_genesis(spec: &GenesisSpec) -> Self {
+        let mut chain = Blockchain::with_genesis(spec.block(), spec.chain_params());
+        chain.checkpoints = Checkpoints::from_config(spec);
+        chain
+    }
+
+    /// Starts a chain on a devnet genesis built for `params`.
+    pub fn with_params(params: ChainParams) -> Self {
+        let spec = GenesisSpec { bits: params.initial_bits, consensus: ConsensusSpec::from(&params), ..GenesisSpec::default() };
+        Blockchain::with_genesis(spec.block(), params)
+    }
+
+    fn with_genesis(genesis: Block, params: ChainParams) -> Self {
+        let mut state = State::default();
+        state.apply_block(&genesis).expect("genesis allocations must fit in a balance");
+        let mut chain = Blockchain {
+            genesis_hash: genesis.hash,
+            blocks: vec![genesis],
+            params,
+            checkpoints: Checkpoints::default(),
+            state,
+            mempool: Mempool::default(),
+            orphans: OrphanPool::default(),
+            tree: HashMap::new(),
+            by_height: HashMap::new(),
+        };
+        chain.rebuild_index();
+        chain
+    }
+
+    /// Recreates the block tree and height index from `blocks`, e.g. after
+    /// deserializing a chain.
+    ///
+    /// Work is accumulated from the first retained block, so totals are only
+    /// comparable between blocks of the same node.
+    pub fn rebuild_index(&mut self) {
+        self.tree.clear();
+        self.by_height = self.blocks.iter().map(|b| (b.header.index, b.hash)).collect();
+        let mut work = U256::zero();
+        for block in &self.blocks {
+            work = work.saturating_add(block_work(block.header.bits));
+            self.tree.insert(block.hash, TreeEntry { block: block.clone(), work });
+        }
+    }
+
+    /// Replays the retained chain into a fresh `State`.
+    ///
+    /// A pruned chain can't be replayed from genesis, so its persisted state is
+    /// kept as-is.
+    pub fn rebuild_state(&mut self) -> Result<(), ChainError> {
+        if self.blocks.first().map(|b| b.header.index) != Some(0) {
+            return Ok(());
+        }
+        let mut state = State::default();
+        for block in &self.blocks {
+            state.apply_block(block).map_err(|reason| ChainError::InvalidBlock {
+                index: block.header.index,
+                hash: block.hash,
+                reason,
+            })?;
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    pub fn tip(&self) -> &Block {
+        self.blocks.last().expect("Chain should have at least genesis")
+    }
+
+    /// Total work of the canonical chain.
+    pub fn tip_work(&self) -> U256 {
+        self.tree.get(&self.tip().hash).map(|e| e.work).unwrap_or_default()
+    }
+
+    /// Balance and nonce of `address` as of the canonical tip.
+    pub fn account(&self, address: &str) -> Account {
+        self.state.account(address)
+    }
+
+    /// Looks up any known block, including those on side branches.
+    pub fn get_block(&self, hash: &Hash256) -> Option<&Block> {
+        self.tree.get(hash).map(|e| &e.block)
+    }
+
+    /// Returns whether `hash` is part of the canonical chain.
+    pub fn is_canonical(&self, hash: &Hash256) -> bool {
+        self.canonical_position(hash).is_some()
+    }
+
+    /// Position of a canonical block in `blocks`, which starts at the oldest retained height.
+    fn canonical_position(&self, hash: &Hash256) -> Option<usize> {
+        let height = self.get_block(hash)?.header.index;
+        if self.by_height.get(&height) != Some(hash) {
+            return None;
+        }
+        Some((height - self.blocks.first()?.header.index) as usize)
+    }
+
+    /// Looks up a block on the canonical chain by hash.
+    pub fn canonical_block(&self, hash: &Hash256) -> Option<&Block> {
+        let pos = self.canonical_position(hash)?;
+        self.blocks.get(pos)
+    }
+
+    /// Looks up the canonical block at `height`, if it hasn't been pruned.
+    pub fn block_at_height(&self, height: u64) -> Option<&Block> {
+        let hash = self.by_height.get(&height)?;
+        self.canonical_block(hash)
+    }
+
+    /// Canonical blocks with heights in `from..=to` that are still retained.
+    pub fn blocks_in_range(&self, from: u64, to: u64) -> &[Block] {
+        let Some(first) = self.blocks.first().map(|b| b.header.index) else {
+            return &[];
+        };
+        if to < first || from > to {
+            return &[];
+        }
+        let start = (from.saturating_sub(first) as usize).min(self.blocks.len());
+        let end = ((to - first) as usize).saturating_add(1).min(self.blocks.len());
+        &self.blocks[start..end]
+    }
+
+    /// Hashes of canonical blocks going back from the tip, one per height at
+    /// first and then at doubling gaps, ending with the oldest retained block.
+    /// A peer answers with the headers after the first of these it shares.
+    pub fn locator(&self) -> Vec<Hash256> {
+        let oldest = self.blocks.first().map(|b| b.header.index).unwrap_or_default();
+        let mut height = self.tip().header.index;
+        let mut step = 1;
+        let mut locator = Vec::new();
+        loop {
+            if let Some(block) = self.block_at_height(height) {
+                locator.push(block.hash);
+            }
+            if height <= oldest {
+                return locator;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step).max(oldest);
+        }
+    }
+
+    /// Up to `max` canonical headers following the first `locator` hash that
+    /// is on our canonical chain; empty if none of them is.
+    pub fn headers_after(&self, locator: &[Hash256], max: usize) -> Vec<BlockHeader> {
+        let Some(pos) = locator.iter().find_map(|hash| self.canonical_position(hash)) else {
+            return Vec::new();
+        };
+        self.blocks[pos + 1..].iter().take(max).map(|b| b.header.clone()).collect()
+    }
+
+    /// Walks back from `hash` to its ancestor at `height`, or the oldest one still known.
+    fn ancestor(&self, hash: &Hash256, height: u64) -> Option<&Block> {
+        let mut block = self.get_block(hash)?;
+        while block.header.index > height {
+            match self.get_block(&block.header.prev_hash) {
+                Some(parent) => block = parent,
+                None => break,
+            }
+        }
+        Some(block)
+    }
+
+    /// Returns the difficulty the chain expects for the block following the tip.
+    pub fn next_bits(&self) -> u32 {
+        self.bits_after(self.tip())
+    }
+
+    /// Returns the difficulty expected for a child of `parent`.
+    fn bits_after(&self, parent: &Block) -> u32 {
+        next_bits(&self.params, parent, |height| self.ancestor(&parent.hash, height))
+    }
+
+    /// Median timestamp the block following the tip must exceed.
+    pub fn median_time_past(&self) -> u128 {
+        self.median_time_after(self.tip())
+    }
+
+    fn median_time_after(&self, parent: &Block) -> u128 {
+        median_time_past(self.params.median_time_span, parent, |height| self.ancestor(&parent.hash, height))
+    }
+
+    /// Re-checks the whole retained canonical chain from scratch.
+    pub fn validate(&self) -> Result<(), ChainError> {
+        self.check_genesis(self.genesis_hash, &self.blocks)?;
+        validate_blocks(&self.blocks, &self.params, &self.checkpoints)
+    }
+
+    /// Checks that a chain claiming `genesis_hash`, and whose retained blocks
+    /// are `blocks`, is on our network.
+    pub fn check_genesis(&self, genesis_hash: Hash256, blocks: &[Block]) -> Result<(), ChainError> {
+        let found = match blocks.first() {
+            Some(first) if first.header.index == 0 => first.hash,
+            _ => genesis_hash,
+        };
+        if found != self.genesis_hash || genesis_hash != self.genesis_hash {
+            return Err(ChainError::WrongGenesis { expected: self.genesis_hash, found });
+        }
+        Ok(())
+    }
+
+    /// Validates headers downloaded ahead of their bodies and returns the total
+    /// work their branch will carry once connected. The run must start on a
+    /// block in our tree and be contiguous.
+    ///
+    /// Everything the headers commit to is checked (links, difficulty,
+    /// timestamps, proof of work and checkpoints), so bodies are only fetched
+    /// for a branch that is valid as far as headers can tell. The first
+    /// `checked` headers passed an earlier call and are only used as context,
+    /// so a run can be validated batch by batch as it downloads.
+    pub fn check_headers(&self, headers: &[BlockHeader], checked: usize) -> Result<U256, ChainError> {
+        let first = headers.first().ok_or(ChainError::Empty)?;
+        // Header-only blocks, so the difficulty and median-time rules apply as for full ones.
+        let blocks: Vec<Block> = headers.iter().map(|h| Block::assemble(h.clone(), Vec::new())).collect();
+        let invalid = |block: &Block, reason: BlockError| ChainError::InvalidBlock {
+            index: block.header.index,
+            hash: block.hash,
+            reason,
+        };
+        let Some(fork) = self.tree.get(&first.prev_hash) else {
+            return Err(invalid(&blocks[0], BlockError::UnknownParent));
+        };
+        if let Some((checkpoint, _)) = self.checkpoints.last()
+            && self.tip().header.index >= checkpoint
+            && first.index <= checkpoint
+        {
+            return Err(invalid(&blocks[0], BlockError::ForkBelowCheckpoint { checkpoint }));
+        }
+
+        let mut work = fork.work;
+        for (pos, block) in blocks.iter().enumerate() {
+            work = work.saturating_add(block_work(block.header.bits));
+            if pos < checked {
+                continue;
+            }
+            if let Some(expected) = self.checkpoints.get(block.header.index).filter(|expected| **expected != block.hash) {
+                return Err(invalid(block, BlockError::CheckpointMismatch { expected: *expected }));
+            }
+            let parent = match pos {
+                0 => &fork.block,
+                _ => &blocks[pos - 1],
+            };
+            let ancestor = |height: u64| match height.checked_sub(first.index) {
+                Some(offset) => blocks.get(offset as usize),
+                None => self.ancestor(&fork.block.hash, height),
+            };
+            let expected_bits = next_bits(&self.params, parent, ancestor);
+            let mtp = median_time_past(self.params.median_time_span, parent, ancestor);
+            check_header(block, parent, expected_bits, mtp, &self.params).map_err(|e| invalid(block, e))?;
+        }
+        Ok(work)
+    }
+
+    /// Validates `block` and files it into the block tree.
+    ///
+    /// The canonical chain is whichever branch carries the most cumulative work;
+    /// if `block` makes a side branch heavier than the tip, the chain rolls back
+    /// to the common ancestor and applies that branch instead.
+    ///
+    /// A block whose parent is unknown is held as an orphan, and orphans are
+    /// connected as soon as the block they wait on is accepted.
+    pub fn add_block(&mut self, block: Block) -> Result<BlockStatus, BlockError> {
+        if !self.tree.contains_key(&block.header.prev_hash) && !self.tree.contains_key(&block.hash) {
+            return self.add_orphan(block);
+        }
+        let hash = block.hash;
+        let result = self.accept_block(block);
+        if result.is_ok() {
+            self.connect_orphans(hash);
+        }
+        result
+    }
+
+    /// Runs `try_add_block` and brings the mempool in line with the outcome.
+    fn accept_block(&mut self, block: Block) -> Result<BlockStatus, BlockError> {
+        let result = self.try_add_block(block);
+        match &result {
+            Ok(BlockStatus::Extended) => self.mempool.refilter(&self.state),
+            Ok(BlockStatus::Reorg(reorg)) => {
+                for block in &reorg.disconnected {
+                    self.mempool.readd(&block.transactions, &self.state);
+                }
+                self.mempool.refilter(&self.state);
+            }
+            Ok(BlockStatus::SideBranch | BlockStatus::Orphan { .. }) => {}
+            Err(e) => println!("❌ Rejected block: {}", e),
+        }
+        result
+    }
+
+    /// Holds a block whose parent is unknown. Only checks that need no parent
+    /// run here: the hash, and proof of work at a target no easier than
+    /// `orphan_target_limit`.
+    fn add_orphan(&mut self, block: Block) -> Result<BlockStatus, BlockError> {
+        if self.orphans.contains(&block.hash) {
+            return Err(BlockError::AlreadyKnown);
+        }
+        check_hash(&block)?;
+        if compact_to_target(block.header.bits) > self.orphan_target_limit() || !self.params.pow.verify(&block.header) {
+            return Err(BlockError::InvalidPow);
+        }
+        let (hash, parent) = (block.hash, block.header.prev_hash);
+        if self.orphans.insert(block) {
+            println!("🧩 Holding orphan block {}", hash);
+        }
+        Ok(BlockStatus::Orphan { missing: self.orphans.missing_ancestor(&parent) })
+    }
+
+    /// Easiest target an orphan may claim: one maximal retarget easier than
+    /// the difficulty the tip expects next, and never easier than genesis.
+    /// Orphans can't be checked against their own expected difficulty, but
+    /// this keeps cheaply mined blocks from filling the pool.
+    fn orphan_target_limit(&self) -> U256 {
+        let limit = compact_to_target(self.params.initial_bits);
+        compact_to_target(self.next_bits()).saturating_mul(U256::from(MAX_RETARGET_FACTOR)).min(limit)
+    }
+
+    /// Connects the orphans descending from `parent`, now that it's in the tree.
+    fn connect_orphans(&mut self, parent: Hash256) {
+        let mut ready = vec![parent];
+        while let Some(hash) = ready.pop() {
+            for child in self.orphans.take_children(&hash) {
+                let child_hash = child.hash;
+                if self.accept_block(child).is_ok() {
+                    ready.push(child_hash);
+                }
+            }
+        }
+    }
+
+    /// Validates `tx` against the tip state and queues it for mining.
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<Hash256, MempoolError> {
+        self.mempool.add(tx, &self.state)
+    }
+
+    fn try_add_block(&mut self, block: Block) -> Result<BlockStatus, BlockError> {
+        if self.tree.contains_key(&block.hash) {
+            return Err(BlockError::AlreadyKnown);
+        }
+        self.checkpoints.check(block.header.index, &block.hash)?;
+        // Once the tip has passed a checkpoint, nothing at or below it can change.
+        if let Some((checkpoint, _)) = self.checkpoints.last()
+            && self.tip().header.index >= checkpoint
+            && block.header.index <= checkpoint
+        {
+            return Err(BlockError::ForkBelowCheckpoint { checkpoint });
+        }
+        let parent = self.tree.get(&block.header.prev_hash).ok_or(BlockError::UnknownParent)?;
+        check_block(
+            &block,
+            &parent.block,
+            self.bits_after(&parent.block),
+            self.median_time_after(&parent.block),
+            &self.params,
+        )?;
+
+        let work = parent.work.saturating_add(block_work(block.header.bits));
+        let extends_tip = block.header.prev_hash == self.tip().hash;
+        let hash = block.hash;
+        self.tree.insert(hash, TreeEntry { block: block.clone(), work });
+
+        if work <= self.tip_work() {
+            return Ok(BlockStatus::SideBranch);
+        }
+        if extends_tip {
+            if let Err(e) = self.state.apply_block(&block) {
+                self.tree.remove(&hash);
+                return Err(e);
+            }
+            self.by_height.insert(block.header.index, block.hash);
+            self.blocks.push(block);
+            return Ok(BlockStatus::Extended);
+        }
+        match self.reorganize(&hash)? {
+            Some(reorg) => {
+                println!(
+                    "🔀 Reorg: disconnected {} block(s), connected {} block(s)",
+                    reorg.disconnected.len(),
+                    reorg.connected.len()
+                );
+                Ok(BlockStatus::Reorg(reorg))
+            }
+            None => {
+                println!("⚠️ Heavier branch forks below pruned history, staying on current chain");
+                Ok(BlockStatus::SideBranch)
+            }
+        }
+    }
+
+    /// Switches the canonical chain to the branch ending at `new_tip`.
+    ///
+    /// Returns `Ok(None)` if the branch can't be traced back to the canonical
+    /// chain. If any block on the branch fails to apply to the state, the state
+    /// is restored, the failing block and its descendants are dropped from the
+    /// tree and the error is returned.
+    fn reorganize(&mut self, new_tip: &Hash256) -> Result<Option<Reorg>, BlockError> {
+        let mut connected = Vec::new();
+        let Some(mut cursor) = self.get_block(new_tip) else {
+            return Ok(None);
+        };
+        let fork_pos = loop {
+            if let Some(pos) = self.canonical_position(&cursor.hash) {
+                break pos;
+            }
+            connected.push(cursor.clone());
+            match self.get_block(&cursor.header.prev_hash) {
+                Some(parent) => cursor = parent,
+                None => return Ok(None),
+            }
+        };
+        connected.reverse();
+        let disconnected = self.blocks[fork_pos + 1..].to_vec();
+
+        for (i, block) in disconnected.iter().enumerate().rev() {
+            if !self.state.revert_block(&block.hash) {
+                println!("⚠️ No undo data for block {}, cannot reorg", block.header.index);
+                self.reapply(&disconnected[i + 1..]);
+                return Ok(None);
+            }
+        }
+        for (i, block) in connected.iter().enumerate() {
+            if let Err(e) = self.state.apply_block(block) {
+                for applied in connected[..i].iter().rev() {
+                    self.state.revert_block(&applied.hash);
+                }
+                self.reapply(&disconnected);
+                for invalid in &connected[i..] {
+                    self.tree.remove(&invalid.hash);
+                }
+                return Err(e);
+            }
+        }
+
+        for block in &disconnected {
+            self.by_height.remove(&block.header.index);
+        }
+        for block in &connected {
+            self.by_height.insert(block.header.index, block.hash);
+        }
+        self.blocks.truncate(fork_pos + 1);
+        self.blocks.extend(connected.iter().cloned());
+        Ok(Some(Reorg { disconnected, connected }))
+    }
+
+    /// Re-applies blocks that were canonical and had been rolled back.
+    fn reapply(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            self.state
+                .apply_block(block)
+                .expect("previously canonical block applies to its own prior state");
+        }
+    }
+
+    /// Forgets every known block below `height`, canonical or not.
+    pub fn forget_below(&mut self, height: u64) {
+        self.by_height.retain(|h, _| *h >= height);
+        self.tree.retain(|hash, e| {
+            let keep = e.block.header.index >= height;
+            if !keep {
+                self.state.forget(hash);
+            }
+            keep
+        });
+    }
+
+    /// Assembles the next block on the tip from the highest-fee mempool
+    /// transactions, paying the subsidy and their fees to `miner_address`.
+    /// The header still needs a nonce that satisfies the proof of work.
+    pub fn block_template(&self, miner_address: &str) -> BlockTemplate {
+        let tip = self.tip();
+        let index = tip.header.index + 1;
+        let selected = self.mempool.select(&self.state, MAX_BLOCK_TRANSACTIONS - 1);
+        let reward = selected.iter().fold(self.params.block_subsidy(index), |sum, tx| sum.saturating_add(tx.fee));
+        let mut transactions = vec![Transaction::coinbase(index, miner_address, reward)];
+        transactions.extend(selected);
+        let now = chrono::Utc::now().timestamp_millis() as u128;
+        let min_timestamp = (self.median_time_past() + 1).max(tip.header.timestamp);
+        let bits = self.next_bits();
+        let header = BlockHeader {
+            version: HEADER_VERSION,
+            index,
+            // Never at or below the median time past or before the tip, even if the clock lags.
+            timestamp: now.max(min_timestamp),
+            prev_hash: tip.hash,
+            merkle_root: Block::compute_merkle_root(&transactions),
+            bits,
+            nonce: 0,
+        };
+        BlockTemplate {
+            header,
+            transactions,
+            min_timestamp,
+            max_timestamp: now + self.params.max_future_drift_ms,
+            target: Hash256::from_u256(compact_to_target(bits)),
+            pow: self.params.pow.config(),
+        }
+    }
+
+    /// Mines the next block on the calling thread. Blocks until a nonce is
+    /// found; the node itself mines through `mining::Miner` instead.
+    pub fn mine_block(&self, miner_address: &str) -> Block {
+        let template = self.block_template(miner_address);
+        let header = solve(&template.header, &*self.params.pow, 1, || false).expect("never cancelled");
+        Block::assemble(header, template.transactions)
+    }
+
+    /// Validates `other` and feeds every block we don't know yet through `add_block`.
+    ///
+    /// Returns whether the canonical tip changed as a result.
+    pub fn sync(&mut self, other: Blockchain) -> bool {
+        if let Err(e) = self
+            .check_genesis(other.genesis_hash, &other.blocks)
+            .and_then(|_| validate_blocks(&other.blocks, &self.params, &self.checkpoints))
+        {
+            println!("❌ Rejected chain: {}", e);
+            return false;
+        }
+        let old_tip = self.tip().hash;
+        for block in other.blocks {
+            if self.tree.contains_key(&block.hash) {
+                continue;
+            }
+            if self.add_block(block).is_err() {
+                break;
+            }
+        }
+        self.tip().hash != old_tip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::verify_pow;
+    use crate::difficulty::{compact_to_target, target_to_compact};
+    use crate::transaction::TxOutput;
+    use crate::utils::now_millis;
+    use ed25519_dalek::SigningKey;
+
+    const EASY_BITS: u32 = 0x207fffff;
+
+    fn test_chain(retarget_interval: u64) -> Blockchain {
+        Blockchain::with_params(ChainParams {
+            initial_bits: EASY_BITS,
+            target_block_time_ms: 60_000,
+            retarget_interval,
+            ..ChainParams::default()
+        })
+    }
+
+    #[test]
+    fn difficulty_changes_only_at_retarget_heights() {
+        let mut chain = test_chain(4);
+        // The first window starts at genesis, timestamped 0, so it looks slow
+        // and the target stays at the easiest allowed.
+        for _ in 0..7 {
+            assert_eq!(chain.next_bits(), EASY_BITS);
+            let block = chain.mine_block("");
+            assert!(chain.add_block(block).is_ok());
+        }
+        // Blocks 4-7 came far faster than one a minute, so the target tightens
+        // by the maximum factor of four.
+        assert_eq!(chain.next_bits(), target_to_compact(compact_to_target(EASY_BITS) / 4));
+    }
+
+    /// Mines a block on top of `parent`, which need not be the tip.
+    /// `tweak` is added to the parent's timestamp, to tell siblings apart.
+    fn mine_on(chain: &Blockchain, parent: &Block, tweak: u128) -> Block {
+        mine_with(parent, tweak, chain.bits_after(parent), Vec::new())
+    }
+
+    fn mine_with_bits(parent: &Block, tweak: u128, bits: u32) -> Block {
+        mine_with(parent, tweak, bits, Vec::new())
+    }
+
+    /// Mines a child of `parent` carrying an empty coinbase followed by `transactions`.
+    fn mine_with(parent: &Block, tweak: u128, bits: u32, transactions: Vec<Transaction>) -> Block {
+        let timestamp = parent.header.timestamp + tweak;
+        let mut transactions = transactions;
+        transactions.insert(0, Transaction::coinbase(parent.header.index + 1, "", 0));
+        (0..)
+            .map(|nonce| Block::new(parent.header.index + 1, timestamp, parent.hash, transactions.clone(), bits, nonce))
+            .find(|b| verify_pow(&b.hash, b.header.bits))
+            .unwrap()
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<Hash256> {
+        blocks.iter().map(|b| b.hash).collect()
+    }
+
+    #[test]
+    fn heavier_branch_wins_and_reorgs_can_be_undone() {
+        let mut chain = test_chain(1_000);
+        let genesis = chain.tip().clone();
+        let a1 = mine_on(&chain, &genesis, 1);
+        assert!(matches!(chain.add_block(a1.clone()), Ok(BlockStatus::Extended)));
+
+        // An equally heavy competitor doesn't displace the chain we are on.
+        let b1 = mine_on(&chain, &genesis, 2);
+        assert!(matches!(chain.add_block(b1.clone()), Ok(BlockStatus::SideBranch)));
+        assert_eq!(chain.tip().hash, a1.hash);
+
+        let b2 = mine_on(&chain, &b1, 2);
+        let Ok(BlockStatus::Reorg(reorg)) = chain.add_block(b2.clone()) else {
+            panic!("expected a reorg");
+        };
+        assert_eq!(hashes(&reorg.disconnected), [a1.hash]);
+        assert_eq!(hashes(&reorg.connected), [b1.hash, b2.hash]);
+        assert_eq!(hashes(&chain.blocks), [genesis.hash, b1.hash, b2.hash]);
+
+        // The old branch is still known, so it can win back the chain.
+        let a2 = mine_on(&chain, &a1, 1);
+        assert!(matches!(chain.add_block(a2.clone()), Ok(BlockStatus::SideBranch)));
+        let a3 = mine_on(&chain, &a2, 1);
+        let Ok(BlockStatus::Reorg(reorg)) = chain.add_block(a3.clone()) else {
+            panic!("expected a reorg");
+        };
+        assert_eq!(hashes(&reorg.disconnected), [b1.hash, b2.hash]);
+        assert_eq!(hashes(&reorg.connected), [a1.hash, a2.hash, a3.hash]);
+        assert_eq!(hashes(&chain.blocks), [genesis.hash, a1.hash, a2.hash, a3.hash]);
+    }
+
+    #[test]
+    fn height_index_follows_the_canonical_chain() {
+        let mut chain = test_chain(1_000);
+        let genesis = chain.tip().clone();
+        let a1 = mine_on(&chain, &genesis, 1);
+        chain.add_block(a1.clone()).unwrap();
+        assert_eq!(chain.block_at_height(1).map(|b| b.hash), Some(a1.hash));
+
+        let b1 = mine_on(&chain, &genesis, 2);
+        let b2 = mine_on(&chain, &b1, 2);
+        chain.add_block(b1.clone()).unwrap();
+        assert!(chain.canonical_block(&b1.hash).is_none());
+        chain.add_block(b2.clone()).unwrap();
+
+        assert_eq!(chain.block_at_height(1).map(|b| b.hash), Some(b1.hash));
+        assert_eq!(chain.block_at_height(2).map(|b| b.hash), Some(b2.hash));
+        assert!(chain.block_at_height(3).is_none());
+        assert!(chain.canonical_block(&a1.hash).is_none());
+        assert!(chain.get_block(&a1.hash).is_some());
+    }
+
+    #[test]
+    fn ranges_are_clipped_to_retained_blocks() {
+        let mut chain = test_chain(1_000);
+        for _ in 0..4 {
+            let block = chain.mine_block("");
+            chain.add_block(block).unwrap();
+        }
+        assert_eq!(hashes(chain.blocks_in_range(1, 2)), hashes(&chain.blocks[1..3]));
+        assert_eq!(chain.blocks_in_range(3, 100).len(), 2);
+        assert!(chain.blocks_in_range(3, 2).is_empty());
+        assert!(chain.blocks_in_range(5, 9).is_empty());
+
+        chain.forget_below(2);
+        assert!(chain.block_at_height(1).is_none());
+        assert_eq!(chain.block_at_height(2).map(|b| b.hash), Some(chain.blocks[2].hash));
+    }
+
+    #[test]
+    fn reorgs_roll_account_state_back_and_forth() {
+        let mut chain = test_chain(1_000);
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let alice_address = hex::encode(alice.verifying_key().as_bytes());
+        let bob_address = hex::encode(SigningKey::from_bytes(&[2; 32]).verifying_key().as_bytes());
+        chain.state.accounts.insert(alice_address.clone(), Account { balance: 100, nonce: 0 });
+
+        let genesis = chain.tip().clone();
+        let pay_bob = Transaction::new_signed(&alice, vec![TxOutput { to: bob_address.clone(), amount: 40 }], 0, 0);
+        let a1 = mine_with(&genesis, 1, chain.bits_after(&genesis), vec![pay_bob]);
+        chain.add_block(a1.clone()).unwrap();
+        assert_eq!(chain.account(&alice_address), Account { balance: 60, nonce: 1 });
+        assert_eq!(chain.account(&bob_address).balance, 40);
+
+        let b1 = mine_on(&chain, &genesis, 2);
+        chain.add_block(b1.clone()).unwrap();
+        let b2 = mine_on(&chain, &b1, 1);
+        assert!(matches!(chain.add_block(b2), Ok(BlockStatus::Reorg(_))));
+        assert_eq!(chain.account(&alice_address), Account { balance: 100, nonce: 0 });
+        assert_eq!(chain.account(&bob_address), Account::default());
+
+        let a2 = mine_on(&chain, &a1, 1);
+        chain.add_block(a2.clone()).unwrap();
+        let a3 = mine_on(&chain, &a2, 1);
+        assert!(matches!(chain.add_block(a3), Ok(BlockStatus::Reorg(_))));
+        assert_eq!(chain.account(&alice_address), Account { balance: 60, nonce: 1 });
+        assert_eq!(chain.account(&bob_address).balance, 40);
+    }
+
+    #[test]
+    fn mined_blocks_pay_the_subsidy_and_fees_to_the_miner() {
+        let mut chain = test_chain(1_000);
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let alice_address = hex::encode(alice.verifying_key().as_bytes());
+        chain.state.accounts.insert(alice_address.clone(), Account { balance: 100, nonce: 0 });
+        let tx = Transaction::new_signed(&alice, vec![TxOutput { to: "bob".into(), amount: 10 }], 3, 0);
+        chain.mempool.add(tx, &chain.state).unwrap();
+
+        let block = chain.mine_block("miner");
+        chain.add_block(block).unwrap();
+        let subsidy = chain.params.block_subsidy(1);
+        assert_eq!(chain.account("miner").balance, subsidy + 3);
+        assert_eq!(chain.account(&alice_address).balance, 87);
+    }
+
+    #[test]
+    fn mined_blocks_stay_after_the_median_time_past() {
+        let mut chain = test_chain(1_000);
+        for _ in 0..2 {
+            let block = mine_on(&chain, chain.tip(), 1);
+            chain.add_block(block).unwrap();
+        }
+        // A tip stamped ahead of the clock, but not far enough to move the median.
+        let ahead = mine_on(&chain, chain.tip(), now_millis() + 60_000);
+        chain.add_block(ahead.clone()).unwrap();
+        assert!(chain.median_time_past() < now_millis());
+
+        let block = chain.mine_block("");
+        assert!(block.header.timestamp >= ahead.header.timestamp);
+        assert!(chain.add_block(block).is_ok());
+    }
+
+    #[test]
+    fn refuses_chains_from_another_network() {
+        let mut chain = test_chain(1_000);
+        let mut other = Blockchain::from_genesis(&GenesisSpec { chain_id: "other".into(), ..GenesisSpec::default() });
+        let block = other.mine_block("");
+        other.add_block(block).unwrap();
+        assert!(matches!(
+            chain.check_genesis(other.genesis_hash, &other.blocks),
+            Err(ChainError::WrongGenesis { found, .. }) if found == other.genesis_hash
+        ));
+        assert!(!chain.sync(other));
+        assert_eq!(chain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn templates_carry_what_a_miner_needs() {
+        let mut chain = test_chain(1_000);
+        let tip = chain.mine_block("");
+        chain.add_block(tip.clone()).unwrap();
+
+        let template = chain.block_template("alice");
+        assert_eq!(template.header.prev_hash, tip.hash);
+        assert_eq!(template.pow, PowConfig::Sha256);
+        assert_eq!(template.target.to_u256(), compact_to_target(template.header.bits));
+        assert!(template.min_timestamp > chain.median_time_past());
+        assert!(template.min_timestamp >= tip.header.timestamp);
+        assert!((template.min_timestamp..=template.max_timestamp).contains(&template.header.timestamp));
+
+        let header = solve(&template.header, &*chain.params.pow, 1, || false).unwrap();
+        let block = Block::assemble(header, template.transactions);
+        assert!(matches!(chain.add_block(block), Ok(BlockStatus::Extended)));
+    }
+
+    #[test]
+    fn blocks_survive_a_json_round_trip() {
+        let mut chain = test_chain(1_000);
+        let block = chain.mine_block("");
+        chain.add_block(block.clone()).unwrap();
+        let json = serde_json::to_string(&chain).unwrap();
+        let restored: Blockchain = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.tip().header, block.header);
+        assert_eq!(restored.tip().hash, block.hash);
+    }
+
+    #[test]
+    fn headers_without_a_version_default_to_the_first_one() {
+        let json = r#"{"index":1,"timestamp":2,"prev_hash":"{z}","merkle_root":"{z}","bits":545259519,"nonce":3}"#
+            .replace("{z}", &Hash256::ZERO.to_hex());
+        let header: BlockHeader = serde_json::from_str(&json).unwrap();
+        assert_eq!(header.version, 1);
+    }
+
+    #[test]
+    fn rejects_known_blocks_and_holds_unconnected_ones() {
+        let mut chain = test_chain(1_000);
+        let genesis = chain.tip().clone();
+        let a1 = mine_on(&chain, &genesis, 1);
+        chain.add_block(a1.clone()).unwrap();
+        assert_eq!(chain.add_block(a1.clone()).unwrap_err(), BlockError::AlreadyKnown);
+        let mut unknown = a1.clone();
+        unknown.hash = Hash256::digest("unknown");
+        let stray = mine_with_bits(&unknown, 1, EASY_BITS);
+        assert!(matches!(chain.add_block(stray), Ok(BlockStatus::Orphan { missing }) if missing == unknown.hash));
+    }
+
+    #[test]
+    fn orphans_connect_once_their_parent_arrives() {
+        let mut chain = test_chain(1_000);
+        let other = test_chain(1_000);
+        let genesis = chain.tip().clone();
+        let a1 = mine_on(&other, &genesis, 1);
+        let a2 = mine_on(&other, &a1, 1);
+        let a3 = mine_on(&other, &a2, 1);
+
+        assert!(matches!(chain.add_block(a3.clone()), Ok(BlockStatus::Orphan { missing }) if missing == a2.hash));
+        assert!(matches!(chain.add_block(a2.clone()), Ok(BlockStatus::Orphan { missing }) if missing == a1.hash));
+        assert_eq!(chain.add_block(a3.clone()).unwrap_err(), BlockError::AlreadyKnown);
+        assert_eq!(chain.orphans.len(), 2);
+
+        assert!(matches!(chain.add_block(a1), Ok(BlockStatus::Extended)));
+        assert_eq!(chain.tip().hash, a3.hash);
+        assert!(chain.orphans.is_empty());
+    }
+
+    #[test]
+    fn orphans_must_carry_work_close_to_the_tip() {
+        let mut chain = test_chain(4);
+        // Two fast retarget windows, each making the target four times harder.
+        for _ in 0..8 {
+            let block = mine_on(&chain, chain.tip(), 1);
+            chain.add_block(block).unwrap();
+        }
+        let tip_target = compact_to_target(chain.next_bits());
+        assert!(tip_target * 4 < compact_to_target(EASY_BITS));
+
+        let mut unknown = chain.tip().clone();
+        unknown.hash = Hash256::digest("unknown");
+        let cheap = mine_with_bits(&unknown, 1, EASY_BITS);
+        assert_eq!(chain.add_block(cheap).unwrap_err(), BlockError::InvalidPow);
+        let near = mine_with_bits(&unknown, 1, target_to_compact(tip_target * 2));
+        assert!(matches!(chain.add_block(near), Ok(BlockStatus::Orphan { .. })));
+    }
+
+    #[test]
+    fn checkpoints_pin_the_chain() {
+        let mut chain = test_chain(1_000);
+        let genesis = chain.tip().clone();
+        let a1 = mine_on(&chain, &genesis, 1);
+        let a2 = mine_on(&chain, &a1, 1);
+        let b2 = mine_on(&chain, &a1, 2);
+        chain.checkpoints = Checkpoints::new([(2, a2.hash)].into());
+
+        chain.add_block(a1).unwrap();
+        assert_eq!(chain.add_block(b2).unwrap_err(), BlockError::CheckpointMismatch { expected: a2.hash });
+        // Below the checkpoint, forks are fine until the tip passes it.
+        let c1 = mine_on(&chain, &genesis, 2);
+        assert!(matches!(chain.add_block(c1), Ok(BlockStatus::SideBranch)));
+
+        chain.add_block(a2).unwrap();
+        let d1 = mine_on(&chain, &genesis, 3);
+        assert_eq!(chain.add_block(d1).unwrap_err(), BlockError::ForkBelowCheckpoint { checkpoint: 2 });
+        assert_eq!(chain.validate(), Ok(()));
+    }
+
+    #[test]
+    fn locators_lead_to_the_headers_after_the_shared_block() {
+        let mut chain = test_chain(1_000);
+        for _ in 0..15 {
+            let next = mine_on(&chain, &chain.tip().clone(), 1);
+            chain.add_block(next).unwrap();
+        }
+        let locator = chain.locator();
+        assert_eq!(locator.first(), Some(&chain.tip().hash));
+        assert_eq!(locator.last(), Some(&chain.genesis_hash));
+        assert!(locator.len() < 16);
+
+        let shared = chain.block_at_height(12).unwrap().hash;
+        let headers = chain.headers_after(&[Hash256::digest("unknown"), shared], 2);
+        assert_eq!(headers.iter().map(|h| h.index).collect::<Vec<_>>(), vec![13, 14]);
+        assert!(chain.headers_after(&[Hash256::digest("unknown")], 2).is_empty());
+    }
+
+    #[test]
+    fn headers_are_checked_batch_by_batch() {
+        let chain = test_chain(1_000);
+        let mut blocks = vec![chain.tip().clone()];
+        for _ in 0..4 {
+            let next = mine_on(&chain, blocks.last().unwrap(), 1);
+            blocks.push(next);
+        }
+        let headers: Vec<BlockHeader> = blocks[1..].iter().map(|b| b.header.clone()).collect();
+
+        let whole = chain.check_headers(&headers, 0).unwrap();
+        assert!(whole > chain.tip_work());
+        assert!(chain.check_headers(&headers[..2], 0).unwrap() < whole);
+        assert_eq!(chain.check_headers(&headers, 2), Ok(whole));
+
+        // Only the new batch is checked, and it must continue the earlier ones.
+        let mut broken = headers.clone();
+        broken[3].prev_hash = Hash256::digest("elsewhere");
+        assert!(matches!(
+            chain.check_headers(&broken, 2),
+            Err(ChainError::InvalidBlock { index: 4, reason: BlockError::PrevHashMismatch, .. })
+        ));
+        let mut unsolved = headers.clone();
+        unsolved[0].nonce += 1;
+        assert!(chain.check_headers(&unsolved, 2).is_ok());
+        assert!(chain.check_headers(&unsolved, 0).is_err());
+
+        assert!(matches!(
+            chain.check_headers(&headers[1..], 0),
+            Err(ChainError::InvalidBlock { reason: BlockError::UnknownParent, .. })
+        ));
+        assert_eq!(chain.check_headers(&[], 0), Err(ChainError::Empty));
+    }
+
+    #[test]
+    fn rejects_blocks_with_unexpected_bits() {
+        let mut chain = test_chain(4);
+        let block = mine_with_bits(&chain.tip().clone(), 1, 0x2000ffff);
+        assert!(matches!(chain.add_block(block), Err(BlockError::BadDifficulty { .. })));
+    }
+}
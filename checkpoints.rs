@@ -0,0 +1,136 @@
+// === checkpoints.rs ===
+
+use crate::encoding::Encoder;
+use crate::genesis::GenesisSpec;
+use crate::hash::Hash256;
+use crate::validation::BlockError;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{env, fs};
+
+const CHECKPOINT_FILE: &str = "checkpoints.json";
+
+/// A checkpoint published after genesis, signed by the network's checkpoint key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedCheckpoint {
+    pub height: u64,
+    pub hash: Hash256,
+    /// Hex-encoded ed25519 signature over `signing_bytes`.
+    pub signature: String,
+}
+
+impl SignedCheckpoint {
+    pub fn sign(key: &SigningKey, genesis: &Hash256, height: u64, hash: Hash256) -> Self {
+        let signature = hex::encode(key.sign(&Self::signing_bytes(genesis, height, &hash)).to_bytes());
+        SignedCheckpoint { height, hash, signature }
+    }
+
+    /// The signature covers the genesis hash too, so a checkpoint can't be
+    /// replayed on another network that shares the key.
+    fn signing_bytes(genesis: &Hash256, height: u64, hash: &Hash256) -> Vec<u8> {
+        let mut enc = Encoder::default();
+        enc.bytes(b"checkpoint").hash(genesis).u64(height).hash(hash);
+        enc.finish()
+    }
+
+    pub fn verify(&self, genesis: &Hash256, key: &VerifyingKey) -> bool {
+        let Some(sig_bytes) = hex::decode(&self.signature).ok().and_then(|b| <[u8; 64]>::try_from(b).ok()) else {
+            return false;
+        };
+        key.verify_strict(&Self::signing_bytes(genesis, self.height, &self.hash), &Signature::from_bytes(&sig_bytes))
+            .is_ok()
+    }
+}
+
+/// Block hashes the canonical chain must contain at given heights.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoints {
+    points: BTreeMap<u64, Hash256>,
+}
+
+impl Checkpoints {
+    pub fn new(points: BTreeMap<u64, Hash256>) -> Self {
+        Checkpoints { points }
+    }
+
+    /// Combines the checkpoints hard-coded in the genesis spec with the signed
+    /// ones in `CHECKPOINT_FILE` (default `checkpoints.json`). Signed
+    /// checkpoints are only honored if they verify against the spec's
+    /// `checkpoint_key`, and never override a hard-coded one.
+    pub fn from_config(spec: &GenesisSpec) -> Self {
+        let mut points = spec.checkpoints.clone();
+        let path = env::var("CHECKPOINT_FILE").unwrap_or_else(|_| CHECKPOINT_FILE.into());
+        let Ok(content) = fs::read_to_string(&path) else {
+            return Checkpoints::new(points);
+        };
+        let signed: Vec<SignedCheckpoint> = match serde_json::from_str(&content) {
+            Ok(signed) => signed,
+            Err(e) => {
+                println!("❌ Ignoring {}: {}", path, e);
+                return Checkpoints::new(points);
+            }
+        };
+        let Some(key) = spec.checkpoint_key() else {
+            println!("❌ Ignoring {}: the genesis spec has no valid checkpoint key", path);
+            return Checkpoints::new(points);
+        };
+        let genesis = spec.hash();
+        for checkpoint in signed {
+            if !checkpoint.verify(&genesis, &key) {
+                println!("❌ Ignoring checkpoint at height {}: bad signature", checkpoint.height);
+                continue;
+            }
+            points.entry(checkpoint.height).or_insert(checkpoint.hash);
+        }
+        Checkpoints::new(points)
+    }
+
+    pub fn get(&self, height: u64) -> Option<&Hash256> {
+        self.points.get(&height)
+    }
+
+    /// Fails if a checkpoint names a block other than `hash` at `height`.
+    pub fn check(&self, height: u64, hash: &Hash256) -> Result<(), BlockError> {
+        match self.points.get(&height) {
+            Some(expected) if expected != hash => Err(BlockError::CheckpointMismatch { expected: *expected }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The highest checkpoint, if any.
+    pub fn last(&self) -> Option<(u64, Hash256)> {
+        self.points.iter().next_back().map(|(height, hash)| (*height, *hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_checkpoints_only_verify_for_their_key_and_network() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let genesis = Hash256::digest("genesis");
+        let checkpoint = SignedCheckpoint::sign(&key, &genesis, 10, Hash256::digest("block"));
+        assert!(checkpoint.verify(&genesis, &key.verifying_key()));
+
+        assert!(!checkpoint.verify(&Hash256::digest("other"), &key.verifying_key()));
+        assert!(!checkpoint.verify(&genesis, &SigningKey::from_bytes(&[8; 32]).verifying_key()));
+        let moved = SignedCheckpoint { height: 11, ..checkpoint.clone() };
+        assert!(!moved.verify(&genesis, &key.verifying_key()));
+        let garbled = SignedCheckpoint { signature: "00".into(), ..checkpoint };
+        assert!(!garbled.verify(&genesis, &key.verifying_key()));
+    }
+
+    #[test]
+    fn allows_only_the_checkpointed_hash() {
+        let (a, b) = (Hash256::digest("a"), Hash256::digest("b"));
+        let checkpoints = Checkpoints::new(BTreeMap::from([(5, a), (9, b)]));
+        assert_eq!(checkpoints.check(5, &a), Ok(()));
+        assert_eq!(checkpoints.check(5, &b), Err(BlockError::CheckpointMismatch { expected: a }));
+        assert_eq!(checkpoints.check(6, &b), Ok(()));
+        assert_eq!(checkpoints.last(), Some((9, b)));
+        assert_eq!(Checkpoints::default().last(), None);
+    }
+}
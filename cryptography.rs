@@ -1,15 +1,16 @@
-// === cryptography.rs ===
-
-use sha2::{Digest, Sha256};
-
-/// Calculates a SHA-256 hash from block contents
-pub fn calculate_hash(index: u64, timestamp: u128, prev_hash: &str, data: &str, nonce: u64) -> String {
-    let input = format!("{}{}{}{}{}", index, timestamp, prev_hash, data, nonce);
-    let hash = Sha256::digest(input.as_bytes());
-    format!("{:x}", hash)
-}
-
-/// Verifies proof-of-work: hash must start with "0000"
-pub fn verify_pow(hash: &str) -> bool {
-    hash.starts_with("0000")
-}
+// === cryptography.rs ===
+
+use crate::blockchain::BlockHeader;
+use crate::difficulty::compact_to_target;
+use crate::encoding::encode_header;
+use crate::hash::Hash256;
+
+/// Calculates a SHA-256 hash over the canonical encoding of a block header
+pub fn calculate_hash(header: &BlockHeader) -> Hash256 {
+    Hash256::digest(encode_header(header))
+}
+
+/// Verifies proof-of-work: hash, read as a big-endian number, must not exceed the target encoded by `bits`
+pub fn verify_pow(hash: &Hash256, bits: u32) -> bool {
+    hash.to_u256() <= compact_to_target(bits)
+}
@@ -0,0 +1,113 @@
+// === difficulty.rs ===
+
+use crate::blockchain::Block;
+use crate::params::ChainParams;
+use primitive_types::{U256, U512};
+
+/// Compact target equivalent to the original "0000" hex prefix rule.
+pub const DEFAULT_BITS: u32 = 0x1f00ffff;
+
+/// Expands a compact `bits` value into a full 256-bit target.
+pub fn compact_to_target(bits: u32) -> U256 {
+    let exponent = bits >> 24;
+    let mantissa = U256::from(bits & 0x007f_ffff);
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent) as usize)
+    } else if exponent > 32 {
+        U256::MAX
+    } else {
+        mantissa << (8 * (exponent - 3) as usize)
+    }
+}
+
+/// Packs a 256-bit target into its compact `bits` form.
+pub fn target_to_compact(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8) as u32;
+    let mut compact = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3) as usize)).low_u32()
+    };
+    // The mantissa is signed; keep the high bit clear by growing the exponent.
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size << 24)
+}
+
+/// Largest factor a single retarget may move the target by, in either direction.
+pub const MAX_RETARGET_FACTOR: u128 = 4;
+
+/// Scales `bits` by how far the observed timespan strayed from the expected one.
+///
+/// The adjustment is clamped to `MAX_RETARGET_FACTOR` in either direction and never
+/// produces a target easier than `limit_bits`.
+pub fn retarget(bits: u32, actual_ms: u128, expected_ms: u128, limit_bits: u32) -> u32 {
+    if expected_ms == 0 {
+        return bits;
+    }
+    let actual = actual_ms.clamp(expected_ms / MAX_RETARGET_FACTOR, expected_ms * MAX_RETARGET_FACTOR).max(1);
+    let limit = compact_to_target(limit_bits);
+    let target = compact_to_target(bits).full_mul(U256::from(actual)) / U512::from(expected_ms);
+    target_to_compact(U256::try_from(target).unwrap_or(U256::MAX).min(limit))
+}
+
+/// Expected number of hashes needed to meet `bits`, i.e. `2^256 / (target + 1)`.
+pub fn block_work(bits: u32) -> U256 {
+    let target = compact_to_target(bits);
+    if target == U256::MAX {
+        return U256::one();
+    }
+    (!target / (target + 1)) + 1
+}
+
+/// Returns the difficulty expected for a child of `parent`.
+///
+/// Every `retarget_interval` blocks the target is rescaled by comparing the
+/// time the last window took against `target_block_time_ms` per block.
+/// `ancestor` resolves a height on `parent`'s branch and is only consulted at
+/// retarget heights; it may return the oldest block it still has.
+pub fn next_bits<'a>(params: &ChainParams, parent: &'a Block, ancestor: impl FnOnce(u64) -> Option<&'a Block>) -> u32 {
+    let height = parent.header.index + 1;
+    if !height.is_multiple_of(params.retarget_interval) {
+        return parent.header.bits;
+    }
+    let first = ancestor(height.saturating_sub(params.retarget_interval)).unwrap_or(parent);
+    let actual = parent.header.timestamp.saturating_sub(first.header.timestamp);
+    let expected = params.target_block_time_ms * (parent.header.index - first.header.index) as u128;
+    retarget(parent.header.bits, actual, expected, params.initial_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_bits_round_trip() {
+        for bits in [DEFAULT_BITS, 0x1d00ffff, 0x207fffff, 0x1b0404cb, 0x03123456] {
+            assert_eq!(target_to_compact(compact_to_target(bits)), bits);
+        }
+    }
+
+    #[test]
+    fn compact_mantissa_stays_positive() {
+        assert_eq!(target_to_compact(U256::from(0x80u32)), 0x02008000);
+    }
+
+    #[test]
+    fn retarget_is_clamped_to_a_factor_of_four() {
+        let bits = 0x1d00ffff;
+        let target = compact_to_target(bits);
+        assert_eq!(compact_to_target(retarget(bits, 1, 1000, 0x207fffff)), target / 4);
+        assert_eq!(compact_to_target(retarget(bits, 100_000, 1000, 0x207fffff)), target * 4);
+        assert_eq!(retarget(bits, 1000, 1000, 0x207fffff), bits);
+    }
+
+    #[test]
+    fn retarget_never_goes_past_the_limit() {
+        assert_eq!(retarget(0x1f00ffff, 4000, 1000, 0x1f00ffff), 0x1f00ffff);
+        // Scaling a target this close to 2^256 must not overflow into a harder one.
+        assert_eq!(retarget(0x207fffff, 720_000, 180_000, 0x207fffff), 0x207fffff);
+    }
+}
@@ -0,0 +1,274 @@
+// === genesis.rs ===
+
+use crate::blockchain::Block;
+use crate::difficulty::DEFAULT_BITS;
+use crate::encoding::Encoder;
+use crate::hash::Hash256;
+use crate::params::ChainParams;
+use crate::pow::{InvalidPowConfig, PowConfig};
+use crate::transaction::Transaction;
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{env, fmt, fs, io};
+
+const GENESIS_FILE: &str = "genesis.json";
+
+/// Describes the first block of a network. Nodes whose specs differ build
+/// different genesis blocks, and the genesis hash is what identifies a network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisSpec {
+    /// Human-readable network name; committed to by the genesis block.
+    pub chain_id: String,
+    /// Genesis timestamp in milliseconds since the epoch.
+    pub timestamp: u128,
+    /// Compact target of the genesis block and the easiest target allowed.
+    pub bits: u32,
+    /// Consensus rules of the network, committed to by the genesis hash.
+    #[serde(default)]
+    pub consensus: ConsensusSpec,
+    /// Proof-of-work algorithm every later block is mined with.
+    #[serde(default)]
+    pub pow: PowConfig,
+    /// Balances credited by the genesis block, keyed by address.
+    #[serde(default)]
+    pub allocations: BTreeMap<String, u64>,
+    /// Hard-coded block hashes by height; see `checkpoints::Checkpoints`.
+    #[serde(default)]
+    pub checkpoints: BTreeMap<u64, Hash256>,
+    /// Hex-encoded ed25519 key that signs checkpoints published later.
+    #[serde(default)]
+    pub checkpoint_key: Option<String>,
+}
+
+/// The consensus parameters a genesis spec fixes; see `ChainParams` for what
+/// each one means. Omitted fields take the devnet defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsensusSpec {
+    pub target_block_time_ms: u128,
+    pub retarget_interval: u64,
+    pub initial_reward: u64,
+    pub halving_interval: u64,
+    pub median_time_span: usize,
+    pub max_future_drift_ms: u128,
+}
+
+impl Default for ConsensusSpec {
+    fn default() -> Self {
+        ConsensusSpec::from(&ChainParams::default())
+    }
+}
+
+impl From<&ChainParams> for ConsensusSpec {
+    fn from(params: &ChainParams) -> Self {
+        ConsensusSpec {
+            target_block_time_ms: params.target_block_time_ms,
+            retarget_interval: params.retarget_interval,
+            initial_reward: params.initial_reward,
+            halving_interval: params.halving_interval,
+            median_time_span: params.median_time_span,
+            max_future_drift_ms: params.max_future_drift_ms,
+        }
+    }
+}
+
+impl ConsensusSpec {
+    fn check(&self) -> Result<(), GenesisError> {
+        let positive = self.target_block_time_ms > 0
+            && self.retarget_interval > 0
+            && self.halving_interval > 0
+            && self.median_time_span > 0;
+        if !positive {
+            return Err(GenesisError::Invalid("block time, intervals and median time span must be positive"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum GenesisError {
+    Read(io::Error),
+    Parse(serde_json::Error),
+    Invalid(&'static str),
+    Pow(InvalidPowConfig),
+}
+
+impl fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenesisError::Read(e) => write!(f, "cannot read genesis file: {}", e),
+            GenesisError::Parse(e) => write!(f, "invalid genesis file: {}", e),
+            GenesisError::Invalid(reason) => write!(f, "invalid genesis file: {}", reason),
+            GenesisError::Pow(e) => write!(f, "invalid genesis file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GenesisError {}
+
+impl Default for GenesisSpec {
+    /// The built-in development network used when no genesis file is present.
+    fn default() -> Self {
+        GenesisSpec {
+            chain_id: "weave-devnet".into(),
+            timestamp: 0,
+            bits: DEFAULT_BITS,
+            consensus: ConsensusSpec::default(),
+            pow: PowConfig::default(),
+            allocations: BTreeMap::new(),
+            checkpoints: BTreeMap::new(),
+            checkpoint_key: None,
+        }
+    }
+}
+
+impl GenesisSpec {
+    pub fn from_file(path: &str) -> Result<Self, GenesisError> {
+        let content = fs::read_to_string(path).map_err(GenesisError::Read)?;
+        let spec: GenesisSpec = serde_json::from_str(&content).map_err(GenesisError::Parse)?;
+        spec.consensus.check()?;
+        spec.pow.algorithm().map_err(GenesisError::Pow)?;
+        Ok(spec)
+    }
+
+    /// Loads the spec named by `GENESIS_FILE`, or `genesis.json`, falling back
+    /// to the built-in devnet when the file doesn't exist.
+    ///
+    /// A file that exists but can't be read or parsed is an error: silently
+    /// starting on the devnet instead is exactly the mix-up this guards against.
+    pub fn from_env() -> Result<Self, GenesisError> {
+        let path = env::var("GENESIS_FILE").unwrap_or_else(|_| GENESIS_FILE.into());
+        match GenesisSpec::from_file(&path) {
+            Err(GenesisError::Read(e)) if e.kind() == io::ErrorKind::NotFound => {
+                println!("🌱 No {} found, using the built-in devnet genesis", path);
+                Ok(GenesisSpec::default())
+            }
+            result => result,
+        }
+    }
+
+    /// Consensus parameters for this network, all fixed by the spec.
+    ///
+    /// Panics on invalid PoW parameters, which `from_file` already rejects.
+    pub fn chain_params(&self) -> ChainParams {
+        ChainParams {
+            initial_bits: self.bits,
+            target_block_time_ms: self.consensus.target_block_time_ms,
+            retarget_interval: self.consensus.retarget_interval,
+            initial_reward: self.consensus.initial_reward,
+            halving_interval: self.consensus.halving_interval,
+            median_time_span: self.consensus.median_time_span,
+            max_future_drift_ms: self.consensus.max_future_drift_ms,
+            pow: self.pow.algorithm().expect("genesis proof-of-work parameters"),
+        }
+    }
+
+    /// Builds the genesis block. Each allocation is minted by a height-0
+    /// coinbase, and `prev_hash` commits to the chain id, consensus rules and
+    /// PoW algorithm so that networks with the same allocations still get
+    /// distinct genesis hashes, and nodes disagreeing on the rules never peer.
+    pub fn block(&self) -> Block {
+        let transactions = self
+            .allocations
+            .iter()
+            .map(|(address, amount)| Transaction::coinbase(0, address, *amount))
+            .collect();
+        let mut network = Encoder::default();
+        network
+            .bytes(self.chain_id.as_bytes())
+            .u128(self.consensus.target_block_time_ms)
+            .u64(self.consensus.retarget_interval)
+            .u64(self.consensus.initial_reward)
+            .u64(self.consensus.halving_interval)
+            .u64(self.consensus.median_time_span as u64)
+            .u128(self.consensus.max_future_drift_ms)
+            .bytes(&serde_json::to_vec(&self.pow).expect("PowConfig serializes"));
+        Block::new(0, self.timestamp, Hash256::digest(network.finish()), transactions, self.bits, 0)
+    }
+
+    pub fn checkpoint_key(&self) -> Option<VerifyingKey> {
+        let bytes: [u8; 32] = hex::decode(self.checkpoint_key.as_ref()?).ok()?.try_into().ok()?;
+        VerifyingKey::from_bytes(&bytes).ok()
+    }
+
+    /// The network id.
+    pub fn hash(&self) -> Hash256 {
+        self.block().hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+
+    fn write_spec(name: &str, json: &str) -> String {
+        let path = env::temp_dir().join(format!("weave-genesis-{}-{}.json", std::process::id(), name));
+        fs::write(&path, json).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn chain_id_and_rules_select_the_network() {
+        let devnet = GenesisSpec::default();
+        assert_eq!(devnet.hash(), GenesisSpec::default().hash());
+
+        let renamed = GenesisSpec { chain_id: "other".into(), ..GenesisSpec::default() };
+        let slower = GenesisSpec {
+            consensus: ConsensusSpec { target_block_time_ms: 120_000, ..ConsensusSpec::default() },
+            ..GenesisSpec::default()
+        };
+        let stingier = GenesisSpec {
+            consensus: ConsensusSpec { initial_reward: 1, ..ConsensusSpec::default() },
+            ..GenesisSpec::default()
+        };
+        assert_ne!(renamed.hash(), devnet.hash());
+        assert_ne!(slower.hash(), devnet.hash());
+        let scrypt = GenesisSpec { pow: PowConfig::Scrypt { log_n: 4, r: 1, p: 1 }, ..GenesisSpec::default() };
+        assert_ne!(stingier.hash(), devnet.hash());
+        assert_ne!(scrypt.hash(), devnet.hash());
+    }
+
+    #[test]
+    fn chain_params_come_from_the_spec() {
+        let spec = GenesisSpec {
+            bits: 0x207fffff,
+            consensus: ConsensusSpec { retarget_interval: 7, halving_interval: 9, ..ConsensusSpec::default() },
+            ..GenesisSpec::default()
+        };
+        let params = spec.chain_params();
+        assert_eq!((params.initial_bits, params.retarget_interval, params.halving_interval), (0x207fffff, 7, 9));
+        assert_eq!(ConsensusSpec::from(&params), spec.consensus);
+    }
+
+    #[test]
+    fn allocations_are_credited_by_a_valid_genesis() {
+        let mut spec = GenesisSpec::default();
+        spec.allocations.insert("alice".into(), 1_000);
+        let chain = Blockchain::from_genesis(&spec);
+        assert_eq!(chain.account("alice").balance, 1_000);
+        assert_eq!(chain.genesis_hash, spec.hash());
+        assert_eq!(chain.validate(), Ok(()));
+    }
+
+    #[test]
+    fn files_default_omitted_rules_and_reject_bad_ones() {
+        let path = write_spec("partial", r#"{"chain_id":"testnet","timestamp":5,"bits":545259519,"consensus":{"retarget_interval":4}}"#);
+        let spec = GenesisSpec::from_file(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(spec.consensus, ConsensusSpec { retarget_interval: 4, ..ConsensusSpec::default() });
+
+        let path = write_spec("zero", r#"{"chain_id":"testnet","timestamp":5,"bits":545259519,"consensus":{"halving_interval":0}}"#);
+        assert!(matches!(GenesisSpec::from_file(&path), Err(GenesisError::Invalid(_))));
+        fs::remove_file(path).unwrap();
+
+        let path = write_spec("scrypt", r#"{"chain_id":"testnet","timestamp":5,"bits":545259519,"pow":{"algorithm":"scrypt","log_n":30,"r":8,"p":1}}"#);
+        assert!(matches!(GenesisSpec::from_file(&path), Err(GenesisError::Pow(_))));
+        fs::remove_file(path).unwrap();
+
+        let path = write_spec("broken", "{");
+        assert!(matches!(GenesisSpec::from_file(&path), Err(GenesisError::Parse(_))));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+// === handshake.rs ===
+
+use crate::blockchain::Blockchain;
+use crate::hash::Hash256;
+use crate::identity::{identity, PeerAnnouncement};
+use crate::utils::now_millis;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the node-to-node API this node speaks. Bump it whenever a
+/// route or message that peers rely on changes shape.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version we can still talk to. Version 1 nodes announce
+/// peers as bare, unsigned URLs.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Optional parts of the protocol this node serves.
+pub const FEATURES: &[&str] = &["announce", "gossip", "headers", "templates"];
+
+/// Features a peer must serve before we register it.
+const REQUIRED_FEATURES: &[&str] = &["announce", "gossip", "headers"];
+
+/// What two nodes tell each other before becoming peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub genesis: Hash256,
+    pub node_id: String,
+    pub best_height: u64,
+    pub user_agent: String,
+    pub features: Vec<String>,
+    /// Where the sender can be reached.
+    pub url: String,
+    /// The sender's signed record of `url`, which we relay to our other peers
+    /// once the handshake succeeds. Optional only so that an older node's
+    /// hello still parses and can be refused for its version.
+    #[serde(default)]
+    pub announcement: Option<PeerAnnouncement>,
+}
+
+/// Why a handshake failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    UnsupportedVersion { theirs: u32, min: u32 },
+    WrongGenesis { expected: Hash256, found: Hash256 },
+    /// The other end is this node.
+    SelfConnection,
+    MissingFeature(String),
+    /// The hello's announcement is missing, invalid or signed by another node.
+    BadAnnouncement(String),
+    /// The other end turned our hello down.
+    Refused(String),
+    /// No hello came back, e.g. because the node predates handshakes.
+    NoReply,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::UnsupportedVersion { theirs, min } => {
+                write!(f, "protocol version {} is older than the minimum {}", theirs, min)
+            }
+            HandshakeError::WrongGenesis { expected, found } => write!(f, "genesis {} does not match ours ({})", found, expected),
+            HandshakeError::SelfConnection => write!(f, "connected to ourselves"),
+            HandshakeError::MissingFeature(feature) => write!(f, "does not support {}", feature),
+            HandshakeError::BadAnnouncement(reason) => write!(f, "bad announcement: {}", reason),
+            HandshakeError::Refused(reason) => write!(f, "refused our handshake: {}", reason),
+            HandshakeError::NoReply => write!(f, "did not answer the handshake"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// The answer to `POST /handshake`: the responder's hello, or why it refused
+/// ours. Externally tagged, as serde can't buffer the hello's `u128`
+/// timestamps to try untagged variants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HandshakeReply {
+    Accepted(Box<Hello>),
+    Refused(String),
+}
+
+impl Hello {
+    /// Our own hello, as of `chain`'s current tip.
+    pub fn ours(chain: &Blockchain, url: &str) -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            genesis: chain.genesis_hash,
+            node_id: identity().node_id(),
+            best_height: chain.tip().header.index,
+            user_agent: USER_AGENT.to_string(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            url: url.to_string(),
+            announcement: Some(identity().announce(&chain.genesis_hash, url)),
+        }
+    }
+
+    /// Checks that a node sending this hello can be a peer of the node that
+    /// sent `ours`.
+    pub fn check(&self, ours: &Hello) -> Result<(), HandshakeError> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion { theirs: self.protocol_version, min: MIN_PROTOCOL_VERSION });
+        }
+        if self.genesis != ours.genesis {
+            return Err(HandshakeError::WrongGenesis { expected: ours.genesis, found: self.genesis });
+        }
+        if self.node_id == ours.node_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        if let Some(missing) = REQUIRED_FEATURES.iter().find(|f| !self.features.iter().any(|theirs| theirs == *f)) {
+            return Err(HandshakeError::MissingFeature(missing.to_string()));
+        }
+        let announcement = self.announcement.as_ref().ok_or_else(|| HandshakeError::BadAnnouncement("missing".into()))?;
+        announcement
+            .verify(&ours.genesis, now_millis())
+            .map_err(|e| HandshakeError::BadAnnouncement(e.to_string()))?;
+        if announcement.node_id != self.node_id {
+            return Err(HandshakeError::BadAnnouncement("signed by another node".into()));
+        }
+        if announcement.address != self.url {
+            return Err(HandshakeError::BadAnnouncement("announces another address".into()));
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    /// A hello from the node whose key is all `seed` bytes.
+    fn hello(seed: u8) -> Hello {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        let genesis = Hash256::digest("genesis");
+        let url = format!("http://node-{}:8080", seed);
+        let now = now_millis();
+        let announcement = PeerAnnouncement::sign(&key, &genesis, &url, now, now + 60_000);
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            genesis,
+            node_id: announcement.node_id.clone(),
+            best_height: 7,
+            user_agent: USER_AGENT.to_string(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            url,
+            announcement: Some(announcement),
+        }
+    }
+
+    #[test]
+    fn compatible_nodes_pass_the_check() {
+        assert_eq!(hello(1).check(&hello(2)), Ok(()));
+        assert!(USER_AGENT.ends_with(env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn incompatible_nodes_are_refused() {
+        let ours = hello(1);
+        let old = Hello { protocol_version: MIN_PROTOCOL_VERSION - 1, ..hello(2) };
+        assert_eq!(
+            old.check(&ours),
+            Err(HandshakeError::UnsupportedVersion { theirs: MIN_PROTOCOL_VERSION - 1, min: MIN_PROTOCOL_VERSION })
+        );
+
+        let other = Hello { genesis: Hash256::digest("elsewhere"), ..hello(2) };
+        assert_eq!(other.check(&ours), Err(HandshakeError::WrongGenesis { expected: ours.genesis, found: other.genesis }));
+
+        assert_eq!(hello(1).check(&ours), Err(HandshakeError::SelfConnection));
+
+        let partial = Hello { features: vec!["gossip".into()], ..hello(2) };
+        assert_eq!(partial.check(&ours), Err(HandshakeError::MissingFeature("announce".into())));
+        assert!(partial.supports("gossip") && !partial.supports("templates"));
+    }
+
+    #[test]
+    fn hellos_must_carry_their_own_announcement() {
+        let ours = hello(1);
+        let unsigned = Hello { announcement: None, ..hello(2) };
+        assert!(matches!(unsigned.check(&ours), Err(HandshakeError::BadAnnouncement(_))));
+        let borrowed = Hello { announcement: hello(3).announcement, ..hello(2) };
+        assert!(matches!(borrowed.check(&ours), Err(HandshakeError::BadAnnouncement(_))));
+        let moved = Hello { url: "http://elsewhere:8080".into(), ..hello(2) };
+        assert_eq!(moved.check(&ours), Err(HandshakeError::BadAnnouncement("announces another address".into())));
+    }
+
+    #[test]
+    fn replies_parse_as_either_a_hello_or_a_refusal() {
+        let theirs = hello(2);
+        let accepted = serde_json::to_string(&HandshakeReply::Accepted(Box::new(theirs.clone()))).unwrap();
+        assert!(matches!(serde_json::from_str(&accepted).unwrap(), HandshakeReply::Accepted(h) if *h == theirs));
+        let refused: HandshakeReply = serde_json::from_str(r#"{ "refused": "nope" }"#).unwrap();
+        assert!(matches!(refused, HandshakeReply::Refused(reason) if reason == "nope"));
+    }
+}
@@ -0,0 +1,160 @@
+// === hash.rs ===
+
+use primitive_types::U256;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+/// A 32-byte hash, shown and serialized as 64 lowercase hex characters.
+#[derive(Clone, Copy, Default)]
+pub struct Hash256(pub [u8; 32]);
+
+impl Hash256 {
+    pub const ZERO: Hash256 = Hash256([0; 32]);
+
+    /// SHA-256 of `data`.
+    pub fn digest(data: impl AsRef<[u8]>) -> Self {
+        Hash256(Sha256::digest(data).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// Reads the hash as a big-endian integer, for comparison against a difficulty target.
+    pub fn to_u256(&self) -> U256 {
+        U256::from_big_endian(&self.0)
+    }
+
+    pub fn from_u256(value: U256) -> Self {
+        let mut bytes = [0u8; 32];
+        value.to_big_endian(&mut bytes);
+        Hash256(bytes)
+    }
+}
+
+// Equality runs in constant time so comparing against a secret-derived hash
+// doesn't leak how many leading bytes matched.
+impl PartialEq for Hash256 {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for Hash256 {}
+
+impl Hash for Hash256 {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl PartialOrd for Hash256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Hash256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash256({})", self.to_hex())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseHashError;
+
+impl fmt::Display for ParseHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected 64 hex characters")
+    }
+}
+
+impl std::error::Error for ParseHashError {}
+
+impl FromStr for Hash256 {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(s, &mut bytes).map_err(|_| ParseHashError)?;
+        Ok(Hash256(bytes))
+    }
+}
+
+impl Serialize for Hash256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips() {
+        let hash = Hash256::digest("weave");
+        assert_eq!(hash.to_hex().len(), 64);
+        assert_eq!(hash.to_hex().parse::<Hash256>(), Ok(hash));
+        assert_eq!(hash.to_string(), hash.to_hex());
+    }
+
+    #[test]
+    fn parsing_accepts_either_case_but_only_full_length() {
+        let hash = Hash256::digest("weave");
+        assert_eq!(hash.to_hex().to_uppercase().parse::<Hash256>(), Ok(hash));
+        assert_eq!(hash.to_hex()[..62].parse::<Hash256>(), Err(ParseHashError));
+        assert_eq!(format!("{}00", hash).parse::<Hash256>(), Err(ParseHashError));
+        assert_eq!("zz".repeat(32).parse::<Hash256>(), Err(ParseHashError));
+    }
+
+    #[test]
+    fn serializes_as_a_hex_string() {
+        let hash = Hash256::digest("weave");
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{}\"", hash));
+        assert_eq!(serde_json::from_str::<Hash256>(&json).unwrap(), hash);
+        assert!(serde_json::from_str::<Hash256>("\"abc\"").is_err());
+    }
+
+    #[test]
+    fn integer_value_is_big_endian() {
+        let mut bytes = [0u8; 32];
+        bytes[31] = 1;
+        assert_eq!(Hash256(bytes).to_u256(), U256::one());
+        bytes[0] = 1;
+        assert_eq!(Hash256(bytes).to_u256(), (U256::one() << 248) + 1);
+        assert_eq!(Hash256::ZERO.to_u256(), U256::zero());
+
+        let (a, b) = (Hash256::digest("a"), Hash256::digest("b"));
+        assert_eq!(a.cmp(&b), a.to_u256().cmp(&b.to_u256()));
+    }
+}
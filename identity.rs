@@ -0,0 +1,322 @@
+// === identity.rs ===
+
+use crate::encoding::Encoder;
+use crate::hash::Hash256;
+use crate::utils::now_millis;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::OnceLock;
+use std::{env, io};
+
+const NODE_KEY_FILE: &str = "node_key";
+
+/// How long our own announcements stay valid.
+const ANNOUNCEMENT_TTL_MS: u128 = 60 * 60 * 1000;
+
+/// Longest validity we accept on anyone's announcement.
+const MAX_ANNOUNCEMENT_TTL_MS: u128 = 24 * 60 * 60 * 1000;
+
+/// How far ahead of our clock an announcement's timestamp may be.
+const MAX_CLOCK_SKEW_MS: u128 = 5 * 60 * 1000;
+
+/// Announcements remembered by `SeenAnnouncements` at most.
+const MAX_SEEN_ANNOUNCEMENTS: usize = 10_000;
+
+static IDENTITY: OnceLock<NodeIdentity> = OnceLock::new();
+
+/// Why the node key could not be loaded.
+#[derive(Debug)]
+pub enum IdentityError {
+    Read(String, io::Error),
+    /// The key file exists but does not hold a hex-encoded 32-byte key.
+    Malformed(String),
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityError::Read(path, e) => write!(f, "cannot read node key {}: {}", path, e),
+            IdentityError::Malformed(path) => write!(f, "{} does not hold a hex-encoded 32-byte key", path),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+/// The node's long-lived keypair. Its public key, hex-encoded, is the node id.
+pub struct NodeIdentity {
+    key: SigningKey,
+}
+
+impl NodeIdentity {
+    pub fn new(key: SigningKey) -> Self {
+        NodeIdentity { key }
+    }
+
+    /// Reads the secret key from `NODE_KEY_FILE` (default `node_key`), or
+    /// generates one and saves it there so the node id survives restarts.
+    pub fn load_or_create() -> Result<Self, IdentityError> {
+        let path = env::var("NODE_KEY_FILE").unwrap_or_else(|_| NODE_KEY_FILE.into());
+        NodeIdentity::load_or_create_at(&path)
+    }
+
+    fn load_or_create_at(path: &str) -> Result<Self, IdentityError> {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let secret = hex::decode(content.trim())
+                    .ok()
+                    .and_then(|b| <[u8; 32]>::try_from(b).ok())
+                    .ok_or_else(|| IdentityError::Malformed(path.to_string()))?;
+                Ok(NodeIdentity::new(SigningKey::from_bytes(&secret)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = NodeIdentity::new(SigningKey::generate(&mut OsRng));
+                if let Err(e) = save_secret(path, &identity.key) {
+                    println!("⚠️ Could not save node key to {}: {}", path, e);
+                }
+                Ok(identity)
+            }
+            Err(e) => Err(IdentityError::Read(path.to_string(), e)),
+        }
+    }
+
+    pub fn node_id(&self) -> String {
+        hex::encode(self.key.verifying_key().to_bytes())
+    }
+
+    /// A fresh announcement that this node can be reached at `address`.
+    pub fn announce(&self, genesis: &Hash256, address: &str) -> PeerAnnouncement {
+        let timestamp = now_millis();
+        PeerAnnouncement::sign(&self.key, genesis, address, timestamp, timestamp + ANNOUNCEMENT_TTL_MS)
+    }
+}
+
+/// Loads or creates this node's identity; `server::run` does so at startup,
+/// before anything asks for `identity`.
+pub fn load_identity() -> Result<&'static NodeIdentity, IdentityError> {
+    if let Some(identity) = IDENTITY.get() {
+        return Ok(identity);
+    }
+    let loaded = NodeIdentity::load_or_create()?;
+    Ok(IDENTITY.get_or_init(|| loaded))
+}
+
+/// This node's identity, as loaded by `load_identity`.
+pub fn identity() -> &'static NodeIdentity {
+    IDENTITY.get().expect("the node identity is loaded at startup")
+}
+
+fn save_secret(path: &str, key: &SigningKey) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(hex::encode(key.to_bytes()).as_bytes())
+}
+
+/// A node's signed claim that it can be reached at `address`, valid from
+/// `timestamp` until `expires`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerAnnouncement {
+    pub address: String,
+    /// The announcing node's id, i.e. its hex-encoded public key.
+    pub node_id: String,
+    pub timestamp: u128,
+    pub expires: u128,
+    /// Hex-encoded ed25519 signature over `signing_bytes`.
+    pub signature: String,
+}
+
+/// Why an announcement was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnouncementError {
+    BadNodeId,
+    BadSignature,
+    Expired,
+    FromTheFuture,
+    /// Valid for longer than `MAX_ANNOUNCEMENT_TTL_MS`.
+    TooLong,
+}
+
+impl fmt::Display for AnnouncementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnouncementError::BadNodeId => write!(f, "node id is not a valid ed25519 public key"),
+            AnnouncementError::BadSignature => write!(f, "signature does not verify"),
+            AnnouncementError::Expired => write!(f, "announcement has expired"),
+            AnnouncementError::FromTheFuture => write!(f, "announcement is timestamped in the future"),
+            AnnouncementError::TooLong => write!(f, "announcement is valid for too long"),
+        }
+    }
+}
+
+impl std::error::Error for AnnouncementError {}
+
+impl PeerAnnouncement {
+    pub fn sign(key: &SigningKey, genesis: &Hash256, address: &str, timestamp: u128, expires: u128) -> Self {
+        let node_id = hex::encode(key.verifying_key().to_bytes());
+        let signature = hex::encode(key.sign(&Self::signing_bytes(genesis, address, &node_id, timestamp, expires)).to_bytes());
+        PeerAnnouncement { address: address.to_string(), node_id, timestamp, expires, signature }
+    }
+
+    /// The signature covers the genesis hash too, so an announcement can't be
+    /// replayed on another network.
+    fn signing_bytes(genesis: &Hash256, address: &str, node_id: &str, timestamp: u128, expires: u128) -> Vec<u8> {
+        let mut enc = Encoder::default();
+        enc.bytes(b"peer")
+            .hash(genesis)
+            .bytes(address.as_bytes())
+            .bytes(node_id.as_bytes())
+            .u128(timestamp)
+            .u128(expires);
+        enc.finish()
+    }
+
+    /// Checks the signature against `node_id`, and that the announcement is
+    /// current as of `now`.
+    pub fn verify(&self, genesis: &Hash256, now: u128) -> Result<(), AnnouncementError> {
+        let key_bytes: [u8; 32] = hex::decode(&self.node_id)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or(AnnouncementError::BadNodeId)?;
+        let key = VerifyingKey::from_bytes(&key_bytes).map_err(|_| AnnouncementError::BadNodeId)?;
+        let sig_bytes: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or(AnnouncementError::BadSignature)?;
+        let message = Self::signing_bytes(genesis, &self.address, &self.node_id, self.timestamp, self.expires);
+        key.verify_strict(&message, &Signature::from_bytes(&sig_bytes))
+            .map_err(|_| AnnouncementError::BadSignature)?;
+
+        if self.timestamp > now + MAX_CLOCK_SKEW_MS {
+            return Err(AnnouncementError::FromTheFuture);
+        }
+        if self.expires <= now {
+            return Err(AnnouncementError::Expired);
+        }
+        if self.expires.saturating_sub(self.timestamp) > MAX_ANNOUNCEMENT_TTL_MS {
+            return Err(AnnouncementError::TooLong);
+        }
+        Ok(())
+    }
+}
+
+/// Announcements we have already acted on, keyed by node id and timestamp,
+/// so a copy relayed back to us by other peers is dropped instead of making
+/// us dial its address again. Entries are forgotten once they expire.
+#[derive(Debug, Default)]
+pub struct SeenAnnouncements {
+    seen: HashMap<(String, u128), u128>,
+}
+
+impl SeenAnnouncements {
+    /// Records `announcement` as of `now`. Returns false if it was seen
+    /// before. At `MAX_SEEN_ANNOUNCEMENTS`, the entry closest to expiring
+    /// makes room.
+    pub fn insert(&mut self, announcement: &PeerAnnouncement, now: u128) -> bool {
+        let key = (announcement.node_id.clone(), announcement.timestamp);
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+        self.seen.retain(|_, expires| *expires > now);
+        if self.seen.len() >= MAX_SEEN_ANNOUNCEMENTS
+            && let Some(soonest) = self.seen.iter().min_by_key(|(_, expires)| **expires).map(|(key, _)| key.clone())
+        {
+            self.seen.remove(&soonest);
+        }
+        self.seen.insert(key, announcement.expires);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u128 = 1_700_000_000_000;
+
+    fn key_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("weave-node-key-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn announcement(seed: u8, timestamp: u128, expires: u128) -> PeerAnnouncement {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        PeerAnnouncement::sign(&key, &Hash256::digest("genesis"), "http://node:8080", timestamp, expires)
+    }
+
+    #[test]
+    fn announcements_verify_only_as_signed_and_on_their_network() {
+        let genesis = Hash256::digest("genesis");
+        let signed = announcement(1, NOW, NOW + ANNOUNCEMENT_TTL_MS);
+        assert_eq!(signed.verify(&genesis, NOW), Ok(()));
+        assert_eq!(signed.verify(&Hash256::digest("elsewhere"), NOW), Err(AnnouncementError::BadSignature));
+
+        let moved = PeerAnnouncement { address: "http://attacker:8080".into(), ..signed.clone() };
+        assert_eq!(moved.verify(&genesis, NOW), Err(AnnouncementError::BadSignature));
+        let stolen = PeerAnnouncement { node_id: announcement(2, NOW, NOW + 1).node_id, ..signed.clone() };
+        assert_eq!(stolen.verify(&genesis, NOW), Err(AnnouncementError::BadSignature));
+        let garbled = PeerAnnouncement { node_id: "not hex".into(), ..signed };
+        assert_eq!(garbled.verify(&genesis, NOW), Err(AnnouncementError::BadNodeId));
+    }
+
+    #[test]
+    fn announcements_are_valid_only_for_their_window() {
+        let genesis = Hash256::digest("genesis");
+        let current = announcement(1, NOW, NOW + ANNOUNCEMENT_TTL_MS);
+        assert_eq!(current.verify(&genesis, NOW + ANNOUNCEMENT_TTL_MS), Err(AnnouncementError::Expired));
+
+        let early = announcement(1, NOW + MAX_CLOCK_SKEW_MS + 1, NOW + ANNOUNCEMENT_TTL_MS);
+        assert_eq!(early.verify(&genesis, NOW), Err(AnnouncementError::FromTheFuture));
+        assert_eq!(announcement(1, NOW + MAX_CLOCK_SKEW_MS, NOW + ANNOUNCEMENT_TTL_MS).verify(&genesis, NOW), Ok(()));
+
+        let forever = announcement(1, NOW, NOW + MAX_ANNOUNCEMENT_TTL_MS + 1);
+        assert_eq!(forever.verify(&genesis, NOW), Err(AnnouncementError::TooLong));
+    }
+
+    #[test]
+    fn node_keys_are_created_once_and_reloaded() {
+        let path = key_path("created");
+        let created = NodeIdentity::load_or_create_at(&path).unwrap();
+        let reloaded = NodeIdentity::load_or_create_at(&path).unwrap();
+        assert_eq!(created.node_id(), reloaded.node_id());
+        assert_ne!(created.node_id(), NodeIdentity::load_or_create_at(&key_path("other")).unwrap().node_id());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn malformed_key_files_are_an_error() {
+        let path = key_path("malformed");
+        fs::write(&path, "deadbeef").unwrap();
+        assert!(matches!(NodeIdentity::load_or_create_at(&path), Err(IdentityError::Malformed(_))));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn repeated_announcements_are_dropped_until_they_expire() {
+        let mut seen = SeenAnnouncements::default();
+        let first = announcement(1, NOW, NOW + 1_000);
+        assert!(seen.insert(&first, NOW));
+        assert!(!seen.insert(&first.clone(), NOW + 10));
+        assert!(seen.insert(&announcement(1, NOW + 1, NOW + 1_000), NOW + 10));
+        assert!(seen.insert(&announcement(2, NOW, NOW + 5_000), NOW + 10));
+        assert_eq!(seen.len(), 3);
+
+        assert!(seen.insert(&announcement(3, NOW + 2_000, NOW + 5_000), NOW + 2_000));
+        assert_eq!(seen.len(), 2);
+    }
+}
@@ -0,0 +1,168 @@
+// === jobs.rs ===
+
+use crate::hash::Hash256;
+use crate::mining::{Miner, MiningOutcome};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Jobs allowed to mine at once; each already uses every miner thread, so
+/// more would only split the same CPUs further.
+pub const MAX_RUNNING_JOBS: usize = 2;
+
+/// Finished jobs kept around for polling; the oldest are forgotten first.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+/// Where a mining job stands, as reported by `GET /jobs/{id}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Mined { hash: Hash256, index: u64 },
+    Failed { reason: String },
+    Cancelled,
+}
+
+/// Returned by `MiningJobs::submit` while `MAX_RUNNING_JOBS` are running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooManyJobs;
+
+impl fmt::Display for TooManyJobs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} mining jobs are already running", MAX_RUNNING_JOBS)
+    }
+}
+
+impl std::error::Error for TooManyJobs {}
+
+struct Job {
+    status: JobStatus,
+    cancel: Arc<AtomicBool>,
+}
+
+/// Mining runs started through the API, each tracked under a numeric id.
+pub struct MiningJobs {
+    miner: Miner,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_id: AtomicU64,
+}
+
+impl MiningJobs {
+    pub fn new(miner: Miner) -> Self {
+        MiningJobs {
+            miner,
+            jobs: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Starts a mining run and returns its job id straight away, unless
+    /// `MAX_RUNNING_JOBS` are already running. Must be called from within
+    /// the Tokio runtime, which awaits the outcome.
+    pub fn submit(self: &Arc<Self>) -> Result<u64, TooManyJobs> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.values().filter(|job| job.status == JobStatus::Running).count() >= MAX_RUNNING_JOBS {
+            return Err(TooManyJobs);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let run = self.miner.start();
+        jobs.insert(id, Job { status: JobStatus::Running, cancel: run.canceller() });
+        drop(jobs);
+
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            let status = match run.wait().await {
+                MiningOutcome::Mined(block) => JobStatus::Mined { hash: block.hash, index: block.header.index },
+                MiningOutcome::Cancelled => JobStatus::Cancelled,
+                outcome @ MiningOutcome::Rejected(_) => JobStatus::Failed { reason: outcome.to_string() },
+            };
+            jobs.finish(id, status);
+        });
+        Ok(id)
+    }
+
+    fn finish(&self, id: u64, status: JobStatus) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            job.status = status;
+        }
+        let finished: Vec<u64> = jobs.iter().filter(|(_, j)| j.status != JobStatus::Running).map(|(id, _)| *id).collect();
+        for old in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+            jobs.remove(old);
+        }
+    }
+
+    pub fn status(&self, id: u64) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.status.clone())
+    }
+
+    /// Asks a running job to stop. Returns false if the job is unknown or
+    /// already finished.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.jobs.lock().unwrap().get(&id) {
+            Some(job) if job.status == JobStatus::Running => {
+                job.cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::params::ChainParams;
+    use std::time::Duration;
+
+    /// A target no test run will ever hit, so jobs run until cancelled.
+    const HARD_BITS: u32 = 0x1d00ffff;
+
+    fn jobs() -> Arc<MiningJobs> {
+        let chain = Blockchain::with_params(ChainParams {
+            initial_bits: HARD_BITS,
+            ..ChainParams::default()
+        });
+        Arc::new(MiningJobs::new(Miner::new(Arc::new(Mutex::new(chain)), 1, String::new())))
+    }
+
+    async fn wait_until_finished(jobs: &MiningJobs, id: u64) -> JobStatus {
+        loop {
+            match jobs.status(id) {
+                Some(JobStatus::Running) => tokio::time::sleep(Duration::from_millis(10)).await,
+                status => return status.unwrap(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn caps_the_running_jobs() {
+        let jobs = jobs();
+        let ids: Vec<u64> = (0..MAX_RUNNING_JOBS).map(|_| jobs.submit().unwrap()).collect();
+        assert_eq!(jobs.submit(), Err(TooManyJobs));
+
+        assert!(jobs.cancel(ids[0]));
+        assert_eq!(wait_until_finished(&jobs, ids[0]).await, JobStatus::Cancelled);
+        let id = jobs.submit().unwrap();
+        assert_eq!(jobs.status(id), Some(JobStatus::Running));
+
+        for id in ids.into_iter().skip(1).chain([id]) {
+            jobs.cancel(id);
+            wait_until_finished(&jobs, id).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn only_running_jobs_can_be_cancelled() {
+        let jobs = jobs();
+        assert!(!jobs.cancel(42));
+        let id = jobs.submit().unwrap();
+        assert!(jobs.cancel(id));
+        wait_until_finished(&jobs, id).await;
+        assert!(!jobs.cancel(id));
+        assert_eq!(jobs.status(42), None);
+    }
+}
@@ -1,12 +1,31 @@
 pub mod blockchain;
+pub mod checkpoints;
 pub mod cryptography;
+pub mod difficulty;
+pub mod encoding;
+pub mod genesis;
+pub mod handshake;
+pub mod identity;
+pub mod hash;
+pub mod jobs;
+pub mod mempool;
+pub mod merkle;
+pub mod mining;
 pub mod networking;
+pub mod orphans;
+pub mod params;
+pub mod peers;
+pub mod pow;
 pub mod prune;
 pub mod rate_limit;
 pub mod routes;
 pub mod server;
+pub mod state;
 pub mod storage;
+pub mod sync;
+pub mod transaction;
 pub mod utils;
+pub mod validation;
 
 pub fn start() -> Result<(), Box<dyn std::error::Error>> {
     tokio::runtime::Runtime::new()?.block_on(async {
@@ -0,0 +1,392 @@
+// === mempool.rs ===
+
+use crate::hash::Hash256;
+use crate::state::{State, StateError};
+use crate::transaction::{Transaction, TxError};
+use crate::utils::now_millis;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::env;
+use std::fmt;
+
+/// Upper bound on transactions `Blockchain::mine_block` puts in one block.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 500;
+
+/// How far past an account's next nonce a pending transaction may be queued.
+const MAX_NONCE_GAP: u64 = 16;
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub received_at: u128,
+    pub size: usize,
+}
+
+/// Why a transaction was not admitted to the mempool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    Invalid(TxError),
+    Rejected(StateError),
+    Duplicate,
+    NonceTooFarAhead { next: u64, found: u64 },
+    /// A transaction with the same sender and nonce is pending with an equal or higher fee.
+    FeeTooLow,
+    /// The pool is at capacity and every entry pays at least as much.
+    Full,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MempoolError::Invalid(e) => write!(f, "invalid transaction: {}", e),
+            MempoolError::Rejected(e) => write!(f, "rejected by state: {}", e),
+            MempoolError::Duplicate => write!(f, "transaction already pending"),
+            MempoolError::NonceTooFarAhead { next, found } => write!(f, "nonce {} is too far ahead of {}", found, next),
+            MempoolError::FeeTooLow => write!(f, "replacement fee must exceed the pending transaction's"),
+            MempoolError::Full => write!(f, "mempool full"),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+/// Pending transactions waiting to be mined, bounded by count, bytes and age.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<Hash256, MempoolEntry>,
+    /// Pending transaction ids per sender, ordered by nonce.
+    by_sender: HashMap<String, BTreeMap<u64, Hash256>>,
+    total_bytes: usize,
+    pub max_count: usize,
+    pub max_bytes: usize,
+    pub max_age_ms: u128,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::from_env()
+    }
+}
+
+impl Mempool {
+    pub fn new(max_count: usize, max_bytes: usize, max_age_ms: u128) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            by_sender: HashMap::new(),
+            total_bytes: 0,
+            max_count,
+            max_bytes,
+            max_age_ms,
+        }
+    }
+
+    /// Reads limits from `MEMPOOL_MAX_TXS`, `MEMPOOL_MAX_BYTES` and `MEMPOOL_MAX_AGE_SECS`.
+    pub fn from_env() -> Self {
+        let max_count = env::var("MEMPOOL_MAX_TXS").ok().and_then(|v| v.parse().ok()).unwrap_or(5_000);
+        let max_bytes = env::var("MEMPOOL_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(5 * 1024 * 1024);
+        let max_age_secs: u128 = env::var("MEMPOOL_MAX_AGE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(3_600);
+        Mempool::new(max_count, max_bytes, max_age_secs * 1000)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, id: &Hash256) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Validates `tx` against `state` and admits it, returning its id.
+    ///
+    /// A pending transaction with the same sender and nonce is replaced only by
+    /// one paying a higher fee. When the pool is full the lowest-fee entries
+    /// are evicted to make room, provided `tx` pays more than each of them;
+    /// otherwise the pool is left untouched.
+    pub fn add(&mut self, tx: Transaction, state: &State) -> Result<Hash256, MempoolError> {
+        tx.verify().map_err(MempoolError::Invalid)?;
+        let id = tx.id();
+        if self.entries.contains_key(&id) {
+            return Err(MempoolError::Duplicate);
+        }
+
+        let account = state.account(&tx.from);
+        if tx.nonce < account.nonce {
+            return Err(MempoolError::Rejected(StateError::BadNonce {
+                from: tx.from.clone(),
+                expected: account.nonce,
+                found: tx.nonce,
+            }));
+        }
+        if tx.nonce > account.nonce + MAX_NONCE_GAP {
+            return Err(MempoolError::NonceTooFarAhead { next: account.nonce, found: tx.nonce });
+        }
+        let cost = tx.total_cost().ok_or(MempoolError::Rejected(StateError::AmountOverflow))?;
+        if cost > account.balance {
+            return Err(MempoolError::Rejected(StateError::InsufficientBalance {
+                from: tx.from.clone(),
+                balance: account.balance,
+                needed: cost,
+            }));
+        }
+
+        let replaced = self.by_sender.get(&tx.from).and_then(|q| q.get(&tx.nonce)).copied();
+        if let Some(existing) = &replaced
+            && self.entries[existing].tx.fee >= tx.fee
+        {
+            return Err(MempoolError::FeeTooLow);
+        }
+
+        self.expire(now_millis());
+        let replaced = replaced.filter(|id| self.entries.contains_key(id));
+        let size = serde_json::to_vec(&tx).map(|b| b.len()).unwrap_or(0);
+        let evicted = self.make_room(size, tx.fee, replaced.as_ref())?;
+        for id in replaced.iter().chain(&evicted) {
+            self.remove(id);
+        }
+
+        self.by_sender.entry(tx.from.clone()).or_default().insert(tx.nonce, id);
+        self.total_bytes += size;
+        self.entries.insert(id, MempoolEntry { tx, received_at: now_millis(), size });
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: &Hash256) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(id)?;
+        self.total_bytes -= entry.size;
+        if let Some(queue) = self.by_sender.get_mut(&entry.tx.from) {
+            queue.remove(&entry.tx.nonce);
+            if queue.is_empty() {
+                self.by_sender.remove(&entry.tx.from);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Picks the entries to evict, lowest fee first, so that a transaction of
+    /// `size` bytes paying `fee` fits once they and `replaced` are gone.
+    /// Nothing is removed here, so a full pool is left as it was.
+    fn make_room(&self, size: usize, fee: u64, replaced: Option<&Hash256>) -> Result<Vec<Hash256>, MempoolError> {
+        let mut count = self.entries.len();
+        let mut bytes = self.total_bytes;
+        if let Some(id) = replaced {
+            count -= 1;
+            bytes -= self.entries[id].size;
+        }
+        let fits = |count: usize, bytes: usize| count < self.max_count && bytes + size <= self.max_bytes;
+        if fits(count, bytes) {
+            return Ok(Vec::new());
+        }
+
+        let mut candidates: Vec<(&Hash256, &MempoolEntry)> = self.entries.iter().filter(|(id, _)| Some(*id) != replaced).collect();
+        candidates.sort_by_key(|(_, e)| (e.tx.fee, Reverse(e.received_at)));
+        let mut evicted = Vec::new();
+        for (id, entry) in candidates {
+            if entry.tx.fee >= fee {
+                break;
+            }
+            evicted.push(*id);
+            count -= 1;
+            bytes -= entry.size;
+            if fits(count, bytes) {
+                return Ok(evicted);
+            }
+        }
+        Err(MempoolError::Full)
+    }
+
+    fn expire(&mut self, now: u128) {
+        let expired: Vec<Hash256> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| now.saturating_sub(e.received_at) > self.max_age_ms)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(&id);
+        }
+    }
+
+    /// Drops expired entries and any that `state` has made stale or unaffordable.
+    /// Call after every new block or reorg.
+    pub fn refilter(&mut self, state: &State) {
+        self.expire(now_millis());
+        let stale: Vec<Hash256> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| {
+                let account = state.account(&e.tx.from);
+                e.tx.nonce < account.nonce || e.tx.total_cost().is_none_or(|cost| cost > account.balance)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            self.remove(&id);
+        }
+    }
+
+    /// Offers transactions from blocks a reorg disconnected back to the pool.
+    pub fn readd(&mut self, transactions: &[Transaction], state: &State) {
+        for tx in transactions {
+            let _ = self.add(tx.clone(), state);
+        }
+    }
+
+    /// Picks up to `max` transactions for the next block, highest fee first.
+    ///
+    /// Each sender's transactions are taken in nonce order starting from the
+    /// account's next nonce, and only while the sender can still afford them.
+    pub fn select(&self, state: &State, max: usize) -> Vec<Transaction> {
+        let mut heap = BinaryHeap::new();
+        let mut balances: HashMap<&str, u64> = HashMap::new();
+        for (sender, queue) in &self.by_sender {
+            let account = state.account(sender);
+            balances.insert(sender, account.balance);
+            if let Some(id) = queue.get(&account.nonce) {
+                let entry = &self.entries[id];
+                heap.push((entry.tx.fee, Reverse(entry.received_at), id));
+            }
+        }
+
+        let mut selected = Vec::new();
+        while selected.len() < max {
+            let Some((_, _, id)) = heap.pop() else { break };
+            let tx = &self.entries[id].tx;
+            let balance = balances.get_mut(tx.from.as_str()).expect("sender seeded above");
+            match tx.total_cost() {
+                Some(cost) if cost <= *balance => *balance -= cost,
+                _ => continue,
+            }
+            selected.push(tx.clone());
+            if let Some(next) = self.by_sender[&tx.from].get(&(tx.nonce + 1)) {
+                let entry = &self.entries[next];
+                heap.push((entry.tx.fee, Reverse(entry.received_at), next));
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Account;
+    use crate::transaction::TxOutput;
+    use ed25519_dalek::SigningKey;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn address(key: &SigningKey) -> String {
+        hex::encode(key.verifying_key().as_bytes())
+    }
+
+    fn transfer(from: &SigningKey, fee: u64, nonce: u64) -> Transaction {
+        Transaction::new_signed(from, vec![TxOutput { to: address(&key(99)), amount: 1 }], fee, nonce)
+    }
+
+    fn funded(keys: &[&SigningKey]) -> State {
+        let mut state = State::default();
+        for key in keys {
+            state.accounts.insert(address(key), Account { balance: 1_000, nonce: 0 });
+        }
+        state
+    }
+
+    #[test]
+    fn replacement_needs_a_higher_fee() {
+        let alice = key(1);
+        let state = funded(&[&alice]);
+        let mut pool = Mempool::new(10, 1 << 20, 60_000);
+        let original = pool.add(transfer(&alice, 5, 0), &state).unwrap();
+        assert_eq!(pool.add(transfer(&alice, 5, 0), &state), Err(MempoolError::Duplicate));
+        let same_fee = Transaction::new_signed(&alice, vec![TxOutput { to: address(&key(99)), amount: 2 }], 5, 0);
+        assert_eq!(pool.add(same_fee, &state), Err(MempoolError::FeeTooLow));
+
+        let replacement = pool.add(transfer(&alice, 6, 0), &state).unwrap();
+        assert!(!pool.contains(&original));
+        assert!(pool.contains(&replacement));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn replacing_in_a_full_pool_uses_the_freed_slot() {
+        let (alice, bob) = (key(1), key(2));
+        let state = funded(&[&alice, &bob]);
+        let mut pool = Mempool::new(2, 1 << 20, 60_000);
+        let low = pool.add(transfer(&alice, 1, 0), &state).unwrap();
+        let other = pool.add(transfer(&bob, 10, 0), &state).unwrap();
+
+        let replacement = pool.add(transfer(&alice, 2, 0), &state).unwrap();
+        assert!(!pool.contains(&low));
+        assert!(pool.contains(&replacement) && pool.contains(&other));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn refusing_a_replacement_keeps_the_original() {
+        let (alice, bob) = (key(1), key(2));
+        let state = funded(&[&alice, &bob]);
+        let original = transfer(&alice, 1, 0);
+        // The replacement is far larger than the original, and room for it
+        // could only come from evicting a better-paying entry.
+        let bytes = serde_json::to_vec(&original).unwrap().len() + serde_json::to_vec(&transfer(&bob, 10, 0)).unwrap().len();
+        let mut pool = Mempool::new(10, bytes, 60_000);
+        let original = pool.add(original, &state).unwrap();
+        let other = pool.add(transfer(&bob, 10, 0), &state).unwrap();
+
+        let outputs = (0..20).map(|_| TxOutput { to: address(&key(99)), amount: 1 }).collect();
+        let bulky = Transaction::new_signed(&alice, outputs, 2, 0);
+        assert_eq!(pool.add(bulky, &state), Err(MempoolError::Full));
+        assert!(pool.contains(&original) && pool.contains(&other));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn a_full_pool_evicts_the_lowest_fee_only_for_a_better_one() {
+        let (alice, bob, carol) = (key(1), key(2), key(3));
+        let state = funded(&[&alice, &bob, &carol]);
+        let mut pool = Mempool::new(2, 1 << 20, 60_000);
+        let cheap = pool.add(transfer(&alice, 1, 0), &state).unwrap();
+        pool.add(transfer(&bob, 5, 0), &state).unwrap();
+
+        assert_eq!(pool.add(transfer(&carol, 1, 0), &state), Err(MempoolError::Full));
+        assert!(pool.contains(&cheap));
+        let better = pool.add(transfer(&carol, 3, 0), &state).unwrap();
+        assert!(!pool.contains(&cheap) && pool.contains(&better));
+    }
+
+    #[test]
+    fn selects_by_fee_in_nonce_order() {
+        let (alice, bob) = (key(1), key(2));
+        let state = funded(&[&alice, &bob]);
+        let mut pool = Mempool::new(10, 1 << 20, 60_000);
+        // Alice's second transaction pays the most, but can't go before her first.
+        pool.add(transfer(&alice, 1, 0), &state).unwrap();
+        pool.add(transfer(&alice, 9, 1), &state).unwrap();
+        pool.add(transfer(&bob, 5, 0), &state).unwrap();
+        let order: Vec<(u64, u64)> = pool.select(&state, 10).iter().map(|tx| (tx.fee, tx.nonce)).collect();
+        assert_eq!(order, [(5, 0), (1, 0), (9, 1)]);
+        assert_eq!(pool.select(&state, 1).len(), 1);
+    }
+
+    #[test]
+    fn refilter_drops_mined_and_unaffordable_transactions() {
+        let (alice, bob) = (key(1), key(2));
+        let mut state = funded(&[&alice, &bob]);
+        let mut pool = Mempool::new(10, 1 << 20, 60_000);
+        pool.add(transfer(&alice, 1, 0), &state).unwrap();
+        let next = pool.add(transfer(&alice, 1, 1), &state).unwrap();
+        pool.add(transfer(&bob, 1, 0), &state).unwrap();
+
+        state.accounts.insert(address(&alice), Account { balance: 1_000, nonce: 1 });
+        state.accounts.insert(address(&bob), Account { balance: 0, nonce: 0 });
+        pool.refilter(&state);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&next));
+    }
+}
@@ -0,0 +1,147 @@
+// === merkle.rs ===
+
+use crate::hash::Hash256;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Leaves and inner nodes are hashed with distinct prefixes so an inner node
+// can never be passed off as a leaf.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+fn hash_leaf(leaf: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(leaf.as_bytes());
+    Hash256(hasher.finalize().into())
+}
+
+fn hash_node(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    Hash256(hasher.finalize().into())
+}
+
+/// Hashes one level of the tree into the next. An unpaired last node is
+/// carried up unchanged rather than hashed with itself.
+fn next_level(level: &[Hash256]) -> Vec<Hash256> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Computes the merkle root over `leaves` (transaction ids, in block order).
+pub fn merkle_root(leaves: &[Hash256]) -> Hash256 {
+    if leaves.is_empty() {
+        return Hash256::digest([]);
+    }
+    let mut level: Vec<Hash256> = leaves.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// A sibling hash on the path from a leaf to the root, and which side it sits on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: Hash256,
+    pub side: Side,
+}
+
+/// Evidence that a leaf is part of a tree with a given root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub leaf_index: usize,
+    pub steps: Vec<ProofStep>,
+}
+
+/// Builds the inclusion proof for `leaves[index]`.
+pub fn merkle_proof(leaves: &[Hash256], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut level: Vec<Hash256> = leaves.iter().map(hash_leaf).collect();
+    let mut pos = index;
+    let mut steps = Vec::new();
+    while level.len() > 1 {
+        let sibling = pos ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            let side = if sibling < pos { Side::Left } else { Side::Right };
+            steps.push(ProofStep { hash: *hash, side });
+        }
+        level = next_level(&level);
+        pos /= 2;
+    }
+    Some(MerkleProof { leaf_index: index, steps })
+}
+
+/// Checks that `proof` links `leaf` to `root`.
+pub fn verify_proof(leaf: &Hash256, proof: &MerkleProof, root: &Hash256) -> bool {
+    let computed = proof.steps.iter().fold(hash_leaf(leaf), |acc, step| match step.side {
+        Side::Left => hash_node(&step.hash, &acc),
+        Side::Right => hash_node(&acc, &step.hash),
+    });
+    computed == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<Hash256> {
+        (0..count).map(|i| Hash256::digest(format!("tx{}", i))).collect()
+    }
+
+    #[test]
+    fn proofs_round_trip_for_every_leaf() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, i).unwrap();
+                assert!(verify_proof(leaf, &proof, &root), "leaf {} of {}", i, count);
+            }
+            assert_eq!(merkle_proof(&leaves, count), None);
+        }
+    }
+
+    #[test]
+    fn proofs_do_not_verify_other_leaves_or_roots() {
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 2).unwrap();
+        assert!(!verify_proof(&leaves[3], &proof, &root));
+        assert!(!verify_proof(&leaves[2], &proof, &merkle_root(&leaves[..4])));
+    }
+
+    #[test]
+    fn inner_nodes_are_not_leaves() {
+        // Without domain separation, the two-leaf root would double as a leaf.
+        let leaves = leaves(2);
+        let inner = hash_node(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]));
+        assert_ne!(merkle_root(&leaves), merkle_root(&[inner]));
+    }
+
+    #[test]
+    fn root_depends_on_order() {
+        let leaves = leaves(4);
+        let mut swapped = leaves.clone();
+        swapped.swap(0, 1);
+        assert_ne!(merkle_root(&leaves), merkle_root(&swapped));
+    }
+}
@@ -0,0 +1,236 @@
+// === mining.rs ===
+
+use crate::blockchain::{Block, BlockHeader, BlockStatus, Blockchain};
+use crate::networking::broadcast_block;
+use crate::pow::PowAlgorithm;
+use crate::validation::BlockError;
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Hashes a worker tries between checks of the stop flag.
+const HASHES_PER_CHECK: u32 = 1024;
+
+/// How often a running search checks whether it was cancelled or went stale.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Searches for a nonce that makes `header` meet its target, on `threads`
+/// worker threads that each own an equal slice of the nonce space. A worker
+/// that exhausts its slice moves its timestamp forward a millisecond and
+/// starts over.
+///
+/// The calling thread polls `cancelled` while the workers run; once it
+/// returns true the search stops and `None` is returned.
+pub fn solve(header: &BlockHeader, pow: &dyn PowAlgorithm, threads: usize, cancelled: impl Fn() -> bool) -> Option<BlockHeader> {
+    let threads = threads.max(1) as u64;
+    let share = u64::MAX / threads;
+    let stop = AtomicBool::new(false);
+    let found: Mutex<Option<BlockHeader>> = Mutex::new(None);
+    let watcher = thread::current();
+
+    thread::scope(|scope| {
+        for worker in 0..threads {
+            let (stop, found, watcher) = (&stop, &found, &watcher);
+            let mut header = header.clone();
+            scope.spawn(move || {
+                let first = worker * share;
+                let last = first + (share - 1);
+                header.nonce = first;
+                while !stop.load(Ordering::Relaxed) {
+                    for _ in 0..HASHES_PER_CHECK {
+                        if pow.verify(&header) {
+                            *found.lock().unwrap() = Some(header);
+                            stop.store(true, Ordering::Relaxed);
+                            watcher.unpark();
+                            return;
+                        }
+                        if header.nonce == last {
+                            header.nonce = first;
+                            header.timestamp += 1;
+                        } else {
+                            header.nonce += 1;
+                        }
+                    }
+                }
+            });
+        }
+        while !stop.load(Ordering::Relaxed) {
+            if cancelled() {
+                stop.store(true, Ordering::Relaxed);
+                break;
+            }
+            thread::park_timeout(POLL_INTERVAL);
+        }
+    });
+    found.into_inner().unwrap()
+}
+
+/// How a mining run ended.
+#[derive(Debug, Clone)]
+pub enum MiningOutcome {
+    /// The block was mined, accepted as the new tip and broadcast.
+    Mined(Block),
+    Cancelled,
+    /// The chain refused the mined block.
+    Rejected(BlockError),
+}
+
+impl fmt::Display for MiningOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MiningOutcome::Mined(block) => write!(f, "mined block {} at height {}", block.hash, block.header.index),
+            MiningOutcome::Cancelled => write!(f, "cancelled"),
+            MiningOutcome::Rejected(e) => write!(f, "mined block rejected: {}", e),
+        }
+    }
+}
+
+/// A mining run in progress.
+pub struct MiningRun {
+    cancel: Arc<AtomicBool>,
+    outcome: oneshot::Receiver<MiningOutcome>,
+}
+
+impl MiningRun {
+    /// Asks the run to stop; it finishes with `MiningOutcome::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// A handle that can cancel the run after `wait` has taken it.
+    pub fn canceller(&self) -> Arc<AtomicBool> {
+        self.cancel.clone()
+    }
+
+    pub async fn wait(self) -> MiningOutcome {
+        self.outcome.await.unwrap_or(MiningOutcome::Cancelled)
+    }
+}
+
+/// Mines blocks for the node on background threads, so the chain lock is
+/// only held to build a template and to add the result.
+pub struct Miner {
+    chain: Arc<Mutex<Blockchain>>,
+    threads: usize,
+    /// Receives the coinbase of every block; an empty address burns it.
+    address: String,
+}
+
+impl Miner {
+    pub fn new(chain: Arc<Mutex<Blockchain>>, threads: usize, address: String) -> Self {
+        Miner { chain, threads: threads.max(1), address }
+    }
+
+    /// Reads `MINER_THREADS` (default: one per CPU) and `MINER_ADDRESS`.
+    pub fn from_env(chain: Arc<Mutex<Blockchain>>) -> Self {
+        let threads = env::var("MINER_THREADS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+        Miner::new(chain, threads, env::var("MINER_ADDRESS").unwrap_or_default())
+    }
+
+    /// Starts mining one block on a background thread. Whenever the tip moves
+    /// under it, e.g. because a peer's block arrived, the search restarts on
+    /// a fresh template.
+    pub fn start(&self) -> MiningRun {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (sender, outcome) = oneshot::channel();
+        let (chain, threads, address, flag) = (self.chain.clone(), self.threads, self.address.clone(), cancel.clone());
+        thread::spawn(move || {
+            let _ = sender.send(mine(&chain, threads, &address, &flag));
+        });
+        MiningRun { cancel, outcome }
+    }
+}
+
+fn mine(chain: &Mutex<Blockchain>, threads: usize, address: &str, cancel: &AtomicBool) -> MiningOutcome {
+    loop {
+        let (template, pow) = {
+            let c = chain.lock().unwrap();
+            (c.block_template(address), c.params.pow.clone())
+        };
+        let parent = template.header.prev_hash;
+        let stale = || cancel.load(Ordering::Relaxed) || chain.lock().unwrap().tip().hash != parent;
+
+        let Some(header) = solve(&template.header, &*pow, threads, stale) else {
+            if cancel.load(Ordering::Relaxed) {
+                return MiningOutcome::Cancelled;
+            }
+            println!("⛏️ Tip moved, restarting mining on the new tip");
+            continue;
+        };
+
+        let block = Block::assemble(header, template.transactions);
+        let status = chain.lock().unwrap().add_block(block.clone());
+        match status {
+            Ok(BlockStatus::Extended | BlockStatus::Reorg(_)) => {
+                broadcast_block(&block, None);
+                return MiningOutcome::Mined(block);
+            }
+            // Another block won the race while we were solving; try again on top of it.
+            Ok(_) => continue,
+            Err(e) => return MiningOutcome::Rejected(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::pow::Sha256Pow;
+
+    const EASY_BITS: u32 = 0x207fffff;
+    /// A target no test run will ever hit.
+    const HARD_BITS: u32 = 0x1d00ffff;
+
+    fn chain(bits: u32) -> Arc<Mutex<Blockchain>> {
+        Arc::new(Mutex::new(Blockchain::with_params(ChainParams {
+            initial_bits: bits,
+            ..ChainParams::default()
+        })))
+    }
+
+    #[test]
+    fn solve_finds_a_nonce_on_any_thread_count() {
+        let template = chain(EASY_BITS).lock().unwrap().block_template("");
+        for threads in [1, 4] {
+            let header = solve(&template.header, &Sha256Pow, threads, || false).unwrap();
+            assert!(Sha256Pow.verify(&header));
+            assert_eq!(header.prev_hash, template.header.prev_hash);
+        }
+    }
+
+    #[test]
+    fn solve_gives_up_once_cancelled() {
+        let template = chain(HARD_BITS).lock().unwrap().block_template("");
+        assert!(solve(&template.header, &Sha256Pow, 2, || true).is_none());
+    }
+
+    #[test]
+    fn mined_blocks_extend_the_chain_and_pay_the_miner() {
+        let chain = chain(EASY_BITS);
+        let miner = Miner::new(chain.clone(), 2, "alice".into());
+        let MiningOutcome::Mined(block) = miner.start().outcome.blocking_recv().unwrap() else {
+            panic!("expected a mined block");
+        };
+        let c = chain.lock().unwrap();
+        assert_eq!(c.tip().hash, block.hash);
+        assert!(c.state.account("alice").balance > 0);
+    }
+
+    #[test]
+    fn cancelled_runs_leave_the_chain_alone() {
+        let chain = chain(HARD_BITS);
+        let tip = chain.lock().unwrap().tip().hash;
+        let run = Miner::new(chain.clone(), 2, String::new()).start();
+        run.cancel();
+        assert!(matches!(run.outcome.blocking_recv().unwrap(), MiningOutcome::Cancelled));
+        assert_eq!(chain.lock().unwrap().tip().hash, tip);
+    }
+}
@@ -1,77 +1,407 @@
-// === networking.rs ===
-
-use crate::blockchain::Block;
-use crate::storage;
-use lazy_static::lazy_static;
-use reqwest::blocking::Client;
-use std::collections::HashSet;
-use std::sync::Mutex;
-
-lazy_static! {
-    static ref KNOWN_PEERS: Mutex<HashSet<String>> = Mutex::new({
-        let initial = storage::load_peers();
-        println!("📥 Loaded {} persisted peers", initial.len());
-        initial.into_iter().collect()
-    });
-    static ref MY_IP: String = detect_public_ip();
-}
-
-pub fn detect_public_ip() -> String {
-    reqwest::blocking::get("https://api.ipify.org")
-        .and_then(|res| res.text())
-        .unwrap_or_else(|_| "127.0.0.1".into())
-}
-
-pub fn add_peer(peer_url: &str) {
-    if peer_url.contains(&*MY_IP) {
-        println!("🔍 Skipping self peer: {}", peer_url);
-        return;
-    }
-    KNOWN_PEERS.lock().unwrap().insert(peer_url.to_string());
-}
-
-pub fn register_peer(peer: String) -> bool {
-    if peer.contains(&*MY_IP) {
-        println!("🔍 Ignored self-peer: {}", peer);
-        return false;
-    }
-    let mut peers = KNOWN_PEERS.lock().unwrap();
-    let added = peers.insert(peer.clone());
-    if added {
-        println!("🔗 Registered peer: {}", &peer);
-        storage::save_peers(&peers.iter().cloned().collect::<Vec<_>>());
-        broadcast_new_peer(&peer);
-    }
-    added
-}
-
-pub fn get_peers() -> Vec<String> {
-    KNOWN_PEERS.lock().unwrap().iter().cloned().collect()
-}
-
-pub fn broadcast_block(block: &Block) {
-    let peers = KNOWN_PEERS.lock().unwrap().clone();
-    let client = Client::new();
-    for peer in peers {
-        let url = format!("{}/block", peer);
-        match client.post(&url).json(&block).send() {
-            Ok(resp) => println!("📡 Block broadcasted to {}: {}", peer, resp.status()),
-            Err(e) => println!("⚠️ Broadcast to {} failed: {}", peer, e),
-        }
-    }
-}
-
-pub fn broadcast_new_peer(peer: &str) {
-    let peers = KNOWN_PEERS.lock().unwrap().clone();
-    let client = Client::new();
-    for other in peers {
-        if other == *peer {
-            continue;
-        }
-        let url = format!("{}/add_peer", other);
-        match client.post(&url).json(&peer).send() {
-            Ok(resp) => println!("📨 Peer announced to {}: {}", other, resp.status()),
-            Err(e) => println!("⚠️ Could not announce peer to {}: {}", other, e),
-        }
-    }
-}
+// === networking.rs ===
+
+use crate::blockchain::{Block, BlockHeader, BlockStatus, Blockchain};
+use crate::handshake::{HandshakeError, HandshakeReply, Hello};
+use crate::hash::Hash256;
+use crate::identity::{AnnouncementError, PeerAnnouncement, SeenAnnouncements};
+use crate::peers::{PeerManager, PeerScore};
+use crate::sync::sync_with_peers;
+use crate::utils::now_millis;
+use lazy_static::lazy_static;
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, OnceCell, Semaphore};
+use tokio::task::{self, JoinSet};
+
+/// Most ancestors fetched from one peer to connect a single orphan.
+const MAX_PARENT_FETCHES: usize = 100;
+
+/// Longest a single request to a peer may take, connecting included.
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most requests to peers in flight at once during a fan-out.
+const MAX_FANOUT: usize = 16;
+
+/// Broadcasts waiting to be sent; once full, new ones are dropped.
+const OUTBOUND_QUEUE: usize = 256;
+
+/// A broadcast waiting in the outbound queue.
+#[derive(Debug)]
+enum Outbound {
+    Block { block: Block, except: Option<String> },
+    Announcement { announcement: PeerAnnouncement, except: String },
+}
+
+lazy_static! {
+    static ref PEERS: Mutex<PeerManager> = Mutex::new({
+        let peers = PeerManager::load();
+        println!("📥 Loaded {} persisted peers", peers.active().len());
+        peers
+    });
+    static ref CLIENT: Client = Client::builder()
+        .timeout(PEER_TIMEOUT)
+        .connect_timeout(PEER_TIMEOUT)
+        .build()
+        .expect("HTTP client must build");
+    static ref MY_IP: OnceCell<String> = OnceCell::new();
+    static ref MY_URL: OnceCell<String> = OnceCell::new();
+    static ref SEEN_ANNOUNCEMENTS: Mutex<SeenAnnouncements> = Mutex::new(SeenAnnouncements::default());
+    static ref OUTBOX: (mpsc::Sender<Outbound>, Mutex<Option<mpsc::Receiver<Outbound>>>) = {
+        let (sender, receiver) = mpsc::channel(OUTBOUND_QUEUE);
+        (sender, Mutex::new(Some(receiver)))
+    };
+}
+
+/// Header naming the node a gossiped block came from, so it isn't echoed back.
+pub const PEER_HEADER: &str = "x-peer";
+
+/// Sends `request` to `peer` and decodes the JSON reply; `None` if the peer
+/// fails, times out or answers with something else. The outcome counts
+/// towards the peer's score.
+async fn get_json<T: DeserializeOwned>(peer: &str, request: RequestBuilder) -> Option<T> {
+    let started = Instant::now();
+    let reply = async { request.send().await.ok()?.json().await.ok() }.await;
+    match reply {
+        Some(_) => PEERS.lock().unwrap().record_success(peer, started.elapsed()),
+        None => report_failure(peer),
+    }
+    reply
+}
+
+pub async fn detect_public_ip() -> String {
+    let ip = match CLIENT.get("https://api.ipify.org").send().await {
+        Ok(res) => res.text().await.ok(),
+        Err(_) => None,
+    };
+    ip.unwrap_or_else(|| "127.0.0.1".into())
+}
+
+async fn my_ip() -> &'static str {
+    MY_IP.get_or_init(detect_public_ip).await
+}
+
+/// The URL peers reach this node at: `NODE_URL`, or our public IP on port 8080.
+pub async fn my_url() -> &'static str {
+    MY_URL
+        .get_or_init(|| async {
+            match std::env::var("NODE_URL") {
+                Ok(url) => url,
+                Err(_) => format!("http://{}:8080", my_ip().await),
+            }
+        })
+        .await
+}
+
+/// Sends our hello to `peer` and returns its own, provided the two nodes are
+/// compatible.
+pub async fn handshake(peer: &str, ours: &Hello) -> Result<Hello, HandshakeError> {
+    let reply: HandshakeReply = get_json(peer, CLIENT.post(format!("{}/handshake", peer)).json(ours))
+        .await
+        .ok_or(HandshakeError::NoReply)?;
+    let theirs = match reply {
+        HandshakeReply::Accepted(theirs) => *theirs,
+        HandshakeReply::Refused(reason) => return Err(HandshakeError::Refused(reason)),
+    };
+    theirs.check(ours)?;
+    Ok(theirs)
+}
+
+/// The hello `peer` sent in our last successful handshake with it.
+pub fn peer_hello(peer: &str) -> Option<Hello> {
+    PEERS.lock().unwrap().hello(peer).cloned()
+}
+
+/// Our hello as of `chain`'s tip.
+pub async fn our_hello(chain: &Mutex<Blockchain>) -> Hello {
+    let url = my_url().await;
+    Hello::ours(&chain.lock().unwrap(), url)
+}
+
+/// Adds `peer` if it completes a handshake with us and the peer manager has
+/// room for it, then syncs in case it is ahead.
+pub async fn register_peer(peer: String, chain: Arc<Mutex<Blockchain>>) -> bool {
+    connect(peer, chain, None).await
+}
+
+/// Acts on a peer announcement relayed to us. Nothing is stored or relayed
+/// unless the signature verifies, the announcement is current and the node
+/// at its address answers our handshake under the announced node id.
+/// Returns whether the peer was added.
+pub async fn receive_announcement(announcement: PeerAnnouncement, chain: Arc<Mutex<Blockchain>>) -> Result<bool, AnnouncementError> {
+    let genesis = chain.lock().unwrap().genesis_hash;
+    let now = now_millis();
+    announcement.verify(&genesis, now)?;
+    if !SEEN_ANNOUNCEMENTS.lock().unwrap().insert(&announcement, now) {
+        return Ok(false);
+    }
+    Ok(connect(announcement.address, chain, Some(announcement.node_id)).await)
+}
+
+/// Shakes hands with `peer` and adds it. If `expected_id` is given, the peer
+/// must answer as that node. Once added, the announcement from its hello is
+/// relayed to our other peers.
+async fn connect(peer: String, chain: Arc<Mutex<Blockchain>>, expected_id: Option<String>) -> bool {
+    if peer.contains(my_ip().await) {
+        println!("🔍 Ignored self-peer: {}", peer);
+        return false;
+    }
+    if is_known_peer(&peer) {
+        return false;
+    }
+    if PEERS.lock().unwrap().is_banned(&peer) {
+        println!("🚫 Refused peer {}: banned", peer);
+        return false;
+    }
+    let ours = our_hello(&chain).await;
+    let theirs = match handshake(&peer, &ours).await {
+        Ok(theirs) => theirs,
+        Err(e) => {
+            println!("🚫 Refused peer {}: {}", peer, e);
+            return false;
+        }
+    };
+    if expected_id.is_some_and(|id| id != theirs.node_id) {
+        println!("🚫 Refused peer {}: answered as a different node than announced", peer);
+        return false;
+    }
+    if theirs.url != peer {
+        println!("🚫 Refused peer {}: announces {} instead", peer, theirs.url);
+        return false;
+    }
+    let added = {
+        let mut peers = PEERS.lock().unwrap();
+        let added = peers.add(&peer);
+        if added {
+            peers.set_hello(&peer, theirs.clone());
+            peers.save();
+        }
+        added
+    };
+    if added {
+        println!("🔗 Registered peer: {} ({}, height {})", &peer, theirs.user_agent, theirs.best_height);
+        if let Some(announcement) = theirs.announcement {
+            broadcast_announcement(announcement, &peer);
+        }
+        tokio::spawn(async move { sync_with_peers(&chain).await });
+    } else {
+        println!("🚫 Refused peer {}: no room among better peers", peer);
+    }
+    added
+}
+
+pub fn get_peers() -> Vec<String> {
+    PEERS.lock().unwrap().active()
+}
+
+pub fn is_known_peer(peer: &str) -> bool {
+    PEERS.lock().unwrap().contains(peer)
+}
+
+/// Shakes hands again with every peer we know, e.g. those loaded from disk at
+/// startup, and drops those that turn out to be incompatible. Peers that
+/// don't answer are left to the usual failure count.
+pub async fn greet_peers(chain: &Mutex<Blockchain>) {
+    let ours = our_hello(chain).await;
+    let results = query_peers(get_peers(), |peer| {
+        let ours = ours.clone();
+        async move { Some(handshake(&peer, &ours).await) }
+    })
+    .await;
+    let mut peers = PEERS.lock().unwrap();
+    for (peer, result) in results {
+        match result {
+            Ok(theirs) => peers.set_hello(&peer, theirs),
+            Err(HandshakeError::NoReply) => {}
+            Err(e) => {
+                println!("🚫 Dropping peer {}: {}", peer, e);
+                peers.remove(&peer);
+            }
+        }
+    }
+    peers.save();
+}
+
+/// Scores of every peer, banned ones included.
+pub fn peer_scores() -> BTreeMap<String, PeerScore> {
+    PEERS.lock().unwrap().scores().clone()
+}
+
+/// Writes the peer list and scores to disk.
+pub fn save_peers() {
+    PEERS.lock().unwrap().save();
+}
+
+/// Notes a failed request to `peer`, dropping it after too many in a row.
+pub fn report_failure(peer: &str) {
+    let mut peers = PEERS.lock().unwrap();
+    if peers.record_failure(peer) {
+        peers.save();
+    }
+}
+
+/// Bans `peer` for sending a block or headers that failed validation.
+pub fn report_invalid(peer: &str) {
+    let mut peers = PEERS.lock().unwrap();
+    peers.record_invalid(peer);
+    peers.save();
+}
+
+/// Sends `request` to each of `peers`, at most `MAX_FANOUT` at a time, and
+/// collects the answers of those that replied. Replies come back in the order
+/// they arrived.
+pub async fn query_peers<T, F, Fut>(peers: Vec<String>, request: F) -> Vec<(String, T)>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Option<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(MAX_FANOUT));
+    let mut tasks = JoinSet::new();
+    for peer in peers {
+        let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+        let reply = request(peer.clone());
+        tasks.spawn(async move {
+            let reply = reply.await;
+            drop(permit);
+            reply.map(|reply| (peer, reply))
+        });
+    }
+    let mut replies = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok(Some(reply)) = joined {
+            replies.push(reply);
+        }
+    }
+    replies
+}
+
+/// Asks `peer` for the height and hash of its tip.
+pub async fn fetch_tip(peer: &str) -> Option<(u64, Hash256)> {
+    #[derive(serde::Deserialize)]
+    struct StatusReply {
+        index: u64,
+        hash: Hash256,
+    }
+    let reply: StatusReply = get_json(peer, CLIENT.get(format!("{}/status", peer))).await?;
+    Some((reply.index, reply.hash))
+}
+
+/// Asks `peer` for the canonical headers following the first `locator` hash it knows.
+pub async fn fetch_headers(peer: &str, locator: &[Hash256]) -> Option<Vec<BlockHeader>> {
+    let locator = locator.iter().map(Hash256::to_hex).collect::<Vec<_>>().join(",");
+    get_json(peer, CLIENT.get(format!("{}/headers", peer)).query(&[("locator", locator)])).await
+}
+
+/// Fetches the canonical blocks at heights `from..=to` from `peer`.
+pub async fn fetch_blocks(peer: &str, from: u64, to: u64) -> Option<Vec<Block>> {
+    get_json(peer, CLIENT.get(format!("{}/blocks", peer)).query(&[("from", from), ("to", to)])).await
+}
+
+/// Fetches a canonical block from `peer` by hash.
+pub async fn fetch_block(peer: &str, hash: &Hash256) -> Option<Block> {
+    let block: Block = get_json(peer, CLIENT.get(format!("{}/block/{}", peer, hash))).await?;
+    (block.hash == *hash).then_some(block)
+}
+
+/// Asks `peer`, which sent us an orphan, for the missing ancestors one at a
+/// time until the orphan connects to our block tree. The chain lock is only
+/// held while each fetched block is added. A peer that serves an invalid
+/// ancestor is banned.
+pub async fn fetch_missing_parents(chain: &Arc<Mutex<Blockchain>>, peer: &str, mut missing: Hash256) {
+    for _ in 0..MAX_PARENT_FETCHES {
+        let Some(block) = fetch_block(peer, &missing).await else {
+            println!("⚠️ Could not fetch block {} from {}", missing, peer);
+            return;
+        };
+        // Validating the block is CPU-bound, so it stays off the runtime's workers.
+        let chain = chain.clone();
+        let status = task::spawn_blocking(move || chain.lock().unwrap().add_block(block)).await;
+        match status {
+            Ok(Ok(BlockStatus::Orphan { missing: next })) => missing = next,
+            Ok(Err(e)) if e.is_peer_fault() => {
+                println!("❌ Block {} from {} rejected: {}", missing, peer, e);
+                report_invalid(peer);
+                return;
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Queues `block` for every known peer except `except`, the one it came from.
+/// Returns at once; the outbound queue does the sending.
+pub fn broadcast_block(block: &Block, except: Option<&str>) {
+    enqueue(Outbound::Block { block: block.clone(), except: except.map(String::from) });
+}
+
+/// Queues `announcement` for every known peer except `except`, the peer it announces.
+pub fn broadcast_announcement(announcement: PeerAnnouncement, except: &str) {
+    enqueue(Outbound::Announcement { announcement, except: except.to_string() });
+}
+
+fn enqueue(message: Outbound) {
+    if OUTBOX.0.try_send(message).is_err() {
+        println!("⚠️ Outbound queue full, dropping broadcast");
+    }
+}
+
+/// Starts the task that delivers queued broadcasts. Each one goes out to all
+/// peers concurrently, at most `MAX_FANOUT` requests at a time, so a slow peer
+/// holds up nothing but its own delivery. Does nothing if already started.
+pub fn start_outbound_queue() {
+    let Some(mut outbox) = OUTBOX.1.lock().unwrap().take() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let permits = Arc::new(Semaphore::new(MAX_FANOUT));
+        while let Some(message) = outbox.recv().await {
+            let me = my_url().await;
+            for peer in get_peers() {
+                let request = match &message {
+                    Outbound::Block { except, .. } if except.as_deref() == Some(peer.as_str()) => continue,
+                    Outbound::Block { block, .. } => CLIENT.post(format!("{}/block", peer)).header(PEER_HEADER, me).json(block),
+                    Outbound::Announcement { except, .. } if *except == peer => continue,
+                    Outbound::Announcement { announcement, .. } => CLIENT.post(format!("{}/announce", peer)).json(announcement),
+                };
+                let is_block = matches!(message, Outbound::Block { .. });
+                let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+                tokio::spawn(async move {
+                    let started = Instant::now();
+                    let result = request.send().await;
+                    drop(permit);
+                    match &result {
+                        Ok(resp) if resp.status().is_success() => PEERS.lock().unwrap().record_success(&peer, started.elapsed()),
+                        _ => report_failure(&peer),
+                    }
+                    match (result, is_block) {
+                        (Ok(resp), true) => println!("📡 Block broadcasted to {}: {}", peer, resp.status()),
+                        (Err(e), true) => println!("⚠️ Broadcast to {} failed: {}", peer, e),
+                        (Ok(resp), false) => println!("📨 Peer announced to {}: {}", peer, resp.status()),
+                        (Err(e), false) => println!("⚠️ Could not announce peer to {}: {}", peer, e),
+                    }
+                });
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn query_peers_keeps_only_the_replies() {
+        let peers: Vec<String> = (0..3 * MAX_FANOUT).map(|i| format!("http://peer-{}", i)).collect();
+        let replies = query_peers(peers, |peer| async move {
+            let n: usize = peer.rsplit('-').next()?.parse().ok()?;
+            n.is_multiple_of(2).then_some(n)
+        })
+        .await;
+        assert_eq!(replies.len(), 3 * MAX_FANOUT / 2);
+        assert!(replies.iter().all(|(peer, n)| *peer == format!("http://peer-{}", n) && n.is_multiple_of(2)));
+    }
+}
@@ -0,0 +1,193 @@
+// === orphans.rs ===
+
+use crate::blockchain::Block;
+use crate::hash::Hash256;
+use std::collections::HashMap;
+use std::env;
+use std::hash::{BuildHasher, RandomState};
+
+#[derive(Debug, Clone)]
+struct OrphanEntry {
+    block: Block,
+    size: usize,
+}
+
+/// Blocks whose parent isn't known yet, held until their ancestors arrive.
+/// Bounded by count and bytes. Evictions pick a random orphan, so a peer
+/// flooding the pool can't predictably push out the orphans others sent.
+#[derive(Debug, Clone)]
+pub struct OrphanPool {
+    blocks: HashMap<Hash256, OrphanEntry>,
+    /// Orphan hashes keyed by the parent they are waiting for.
+    by_parent: HashMap<Hash256, Vec<Hash256>>,
+    total_bytes: usize,
+    pub max_count: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::from_env()
+    }
+}
+
+impl OrphanPool {
+    pub fn new(max_count: usize, max_bytes: usize) -> Self {
+        OrphanPool {
+            blocks: HashMap::new(),
+            by_parent: HashMap::new(),
+            total_bytes: 0,
+            max_count,
+            max_bytes,
+        }
+    }
+
+    /// Reads limits from `ORPHAN_MAX_BLOCKS` and `ORPHAN_MAX_BYTES`.
+    pub fn from_env() -> Self {
+        let max_count = env::var("ORPHAN_MAX_BLOCKS").ok().and_then(|v| v.parse().ok()).unwrap_or(100);
+        let max_bytes = env::var("ORPHAN_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(16 * 1024 * 1024);
+        OrphanPool::new(max_count, max_bytes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Stores `block`, evicting random orphans to stay within limits.
+    /// Returns false if the block is already held or could never fit.
+    pub fn insert(&mut self, block: Block) -> bool {
+        let size = serde_json::to_vec(&block).map(|b| b.len()).unwrap_or(0);
+        if size > self.max_bytes || self.max_count == 0 || self.blocks.contains_key(&block.hash) {
+            return false;
+        }
+        while self.blocks.len() + 1 > self.max_count || self.total_bytes + size > self.max_bytes {
+            let Some(victim) = self.random_orphan() else {
+                break;
+            };
+            self.remove(&victim);
+        }
+
+        let hash = block.hash;
+        self.by_parent.entry(block.header.prev_hash).or_default().push(hash);
+        self.total_bytes += size;
+        self.blocks.insert(hash, OrphanEntry { block, size });
+        true
+    }
+
+    /// Picks an orphan using a freshly seeded hasher, so which one is chosen
+    /// can't be predicted from the order orphans arrived in.
+    fn random_orphan(&self) -> Option<Hash256> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let pick = RandomState::new().hash_one(self.blocks.len()) as usize % self.blocks.len();
+        self.blocks.keys().nth(pick).copied()
+    }
+
+    fn remove(&mut self, hash: &Hash256) -> Option<Block> {
+        let entry = self.blocks.remove(hash)?;
+        self.total_bytes -= entry.size;
+        let parent = entry.block.header.prev_hash;
+        if let Some(children) = self.by_parent.get_mut(&parent) {
+            children.retain(|h| h != hash);
+            if children.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+        Some(entry.block)
+    }
+
+    /// Removes and returns the orphans waiting on `parent`.
+    pub fn take_children(&mut self, parent: &Hash256) -> Vec<Block> {
+        let children = self.by_parent.get(parent).cloned().unwrap_or_default();
+        children.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    /// Follows `hash`'s ancestry through the pool and returns the first
+    /// ancestor that isn't in it: the block that has to be fetched next.
+    pub fn missing_ancestor(&self, hash: &Hash256) -> Hash256 {
+        let mut current = *hash;
+        while let Some(entry) = self.blocks.get(&current) {
+            current = entry.block.header.prev_hash;
+        }
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block at `height` whose parent has hash `parent`; orphans aren't validated here.
+    fn block(parent: Hash256, height: u64) -> Block {
+        Block::new(height, height as u128, parent, Vec::new(), 0x207fffff, 0)
+    }
+
+    fn chain_of(len: usize) -> Vec<Block> {
+        let mut blocks = vec![block(Hash256::digest("root"), 1)];
+        while blocks.len() < len {
+            let parent = blocks.last().unwrap();
+            blocks.push(block(parent.hash, parent.header.index + 1));
+        }
+        blocks
+    }
+
+    #[test]
+    fn children_are_released_with_their_parent() {
+        let blocks = chain_of(3);
+        let mut pool = OrphanPool::new(10, usize::MAX);
+        assert!(pool.insert(blocks[2].clone()));
+        assert!(pool.insert(blocks[1].clone()));
+        assert!(!pool.insert(blocks[1].clone()));
+        assert_eq!(pool.missing_ancestor(&blocks[2].hash), blocks[0].hash);
+
+        let released = pool.take_children(&blocks[0].hash);
+        assert_eq!(released.iter().map(|b| b.hash).collect::<Vec<_>>(), [blocks[1].hash]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.take_children(&blocks[1].hash).len(), 1);
+        assert!(pool.is_empty());
+        assert_eq!(pool.total_bytes, 0);
+    }
+
+    #[test]
+    fn stays_within_its_limits() {
+        let blocks = chain_of(20);
+        let mut pool = OrphanPool::new(5, usize::MAX);
+        for block in &blocks {
+            assert!(pool.insert(block.clone()));
+            assert!(pool.len() <= 5);
+        }
+        // The newest orphan is always kept; the rest are random survivors.
+        assert!(pool.contains(&blocks[19].hash));
+        assert_eq!(pool.by_parent.values().map(Vec::len).sum::<usize>(), 5);
+
+        let size = serde_json::to_vec(&blocks[0]).unwrap().len();
+        let mut pool = OrphanPool::new(100, size * 2);
+        for block in &blocks {
+            pool.insert(block.clone());
+            assert!(pool.total_bytes <= size * 2);
+        }
+        assert!(!OrphanPool::new(100, size - 1).insert(blocks[0].clone()));
+    }
+
+    #[test]
+    fn eviction_does_not_always_hit_the_oldest() {
+        let blocks = chain_of(40);
+        let survived = (0..20).any(|_| {
+            let mut pool = OrphanPool::new(2, usize::MAX);
+            blocks.iter().take(3).for_each(|b| {
+                pool.insert(b.clone());
+            });
+            pool.contains(&blocks[0].hash)
+        });
+        assert!(survived);
+    }
+}
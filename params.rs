@@ -0,0 +1,77 @@
+// === params.rs ===
+
+use crate::difficulty::DEFAULT_BITS;
+use crate::pow::{PowAlgorithm, Sha256Pow};
+use std::sync::Arc;
+
+/// Consensus parameters that may differ between networks, fixed by the
+/// genesis spec; see `genesis::GenesisSpec::chain_params`.
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    /// Compact target of the genesis block; also the easiest target allowed.
+    pub initial_bits: u32,
+    /// Desired spacing between blocks, in milliseconds.
+    pub target_block_time_ms: u128,
+    /// Number of blocks between difficulty adjustments.
+    pub retarget_interval: u64,
+    /// Coinbase subsidy of the first block, before any halving.
+    pub initial_reward: u64,
+    /// Number of blocks after which the subsidy halves.
+    pub halving_interval: u64,
+    /// Number of preceding blocks whose median timestamp a new block must exceed.
+    pub median_time_span: usize,
+    /// How far past the local clock a block's timestamp may be, in milliseconds.
+    pub max_future_drift_ms: u128,
+    /// Proof-of-work function; set by the genesis spec, never the environment.
+    pub pow: Arc<dyn PowAlgorithm>,
+}
+
+impl ChainParams {
+    /// New coins a block at `height` may mint: `initial_reward`, halved every
+    /// `halving_interval` blocks until it reaches zero.
+    pub fn block_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_reward >> halvings
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            initial_bits: DEFAULT_BITS,
+            target_block_time_ms: 60_000,
+            retarget_interval: 20,
+            initial_reward: 5_000_000_000,
+            halving_interval: 210_000,
+            median_time_span: 11,
+            max_future_drift_ms: 2 * 60 * 60 * 1000,
+            pow: Arc::new(Sha256Pow),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsidy_halves_until_it_runs_out() {
+        let params = ChainParams { initial_reward: 100, halving_interval: 10, ..ChainParams::default() };
+        assert_eq!(params.block_subsidy(0), 100);
+        assert_eq!(params.block_subsidy(9), 100);
+        assert_eq!(params.block_subsidy(10), 50);
+        assert_eq!(params.block_subsidy(25), 25);
+        assert_eq!(params.block_subsidy(70), 0);
+        assert_eq!(params.block_subsidy(u64::MAX), 0);
+    }
+
+    #[test]
+    fn total_supply_is_bounded() {
+        let params = ChainParams { initial_reward: u64::MAX, halving_interval: 1, ..ChainParams::default() };
+        let supply = (0..128).fold(0u128, |sum, height| sum + params.block_subsidy(height) as u128);
+        assert!(supply < 2 * u64::MAX as u128);
+    }
+}
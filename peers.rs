@@ -0,0 +1,317 @@
+// === peers.rs ===
+
+use crate::handshake::Hello;
+use crate::storage;
+use crate::utils::now_millis;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::time::Duration;
+
+/// Peers kept at most, unless `MAX_PEERS` says otherwise.
+const DEFAULT_MAX_PEERS: usize = 64;
+
+/// How long a misbehaving peer stays banned, unless `PEER_BAN_SECS` says otherwise.
+const DEFAULT_BAN_SECS: u64 = 24 * 60 * 60;
+
+/// Requests in a row a peer may fail before it is dropped as unreachable.
+const MAX_CONSECUTIVE_FAILURES: u32 = 10;
+
+/// What we have seen of a peer, used to rank it against the others.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerScore {
+    /// Moving average of how long its replies took.
+    pub latency_ms: Option<u64>,
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    /// Blocks or headers it sent that failed validation.
+    pub invalid_blocks: u64,
+    /// When it last answered or sent us something, in milliseconds.
+    pub last_seen: Option<u128>,
+    /// Set while the peer is banned: when, in milliseconds, the ban ends.
+    #[serde(default)]
+    pub banned_until: Option<u128>,
+}
+
+impl PeerScore {
+    /// Higher is better; a new peer starts at zero. Useful answers count up,
+    /// to a point, while failures in a row, invalid blocks and slow replies
+    /// count down.
+    pub fn score(&self) -> i64 {
+        self.successes.min(1000) as i64
+            - 10 * self.consecutive_failures as i64
+            - 100 * self.invalid_blocks as i64
+            - self.latency_ms.unwrap_or_default() as i64 / 100
+    }
+}
+
+/// The peers we talk to, at most `max_peers` of them, with what we know of
+/// each. Banned peers are kept, inactive, until their ban ends.
+#[derive(Debug, Clone)]
+pub struct PeerManager {
+    peers: BTreeMap<String, PeerScore>,
+    /// The hello each active peer sent when we last shook hands with it.
+    /// Not persisted: peers loaded from disk are greeted again at startup.
+    hellos: HashMap<String, Hello>,
+    max_peers: usize,
+    ban_duration_ms: u128,
+}
+
+impl PeerManager {
+    pub fn new(max_peers: usize, ban_duration: Duration) -> Self {
+        PeerManager {
+            peers: BTreeMap::new(),
+            hellos: HashMap::new(),
+            max_peers: max_peers.max(1),
+            ban_duration_ms: ban_duration.as_millis(),
+        }
+    }
+
+    /// Reads `MAX_PEERS` and `PEER_BAN_SECS`.
+    pub fn from_env() -> Self {
+        let max_peers = env::var("MAX_PEERS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_PEERS);
+        let ban_secs = env::var("PEER_BAN_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_BAN_SECS);
+        PeerManager::new(max_peers, Duration::from_secs(ban_secs))
+    }
+
+    /// Restores the persisted peer list along with every peer's score and ban.
+    pub fn load() -> Self {
+        let mut manager = PeerManager::from_env();
+        manager.peers = storage::load_peer_scores();
+        for peer in storage::load_peers() {
+            manager.peers.entry(peer).or_default();
+        }
+        manager
+    }
+
+    pub fn save(&self) {
+        storage::save_peers(&self.active());
+        storage::save_peer_scores(&self.peers);
+    }
+
+    /// Every peer we currently talk to.
+    pub fn active(&self) -> Vec<String> {
+        self.peers.iter().filter(|(_, score)| score.banned_until.is_none()).map(|(peer, _)| peer.clone()).collect()
+    }
+
+    pub fn contains(&self, peer: &str) -> bool {
+        self.peers.get(peer).is_some_and(|score| score.banned_until.is_none())
+    }
+
+    /// Scores of all peers, banned ones included.
+    pub fn scores(&self) -> &BTreeMap<String, PeerScore> {
+        &self.peers
+    }
+
+    pub fn is_banned(&self, peer: &str) -> bool {
+        self.peers.get(peer).and_then(|score| score.banned_until).is_some_and(|until| until > now_millis())
+    }
+
+    /// Adds `peer` unless it is already active or still banned; a peer whose
+    /// ban has ended comes back with its old score. At the cap, the worst
+    /// active peer makes room if it scores below the newcomer, otherwise
+    /// `peer` is refused.
+    pub fn add(&mut self, peer: &str) -> bool {
+        if self.contains(peer) || self.is_banned(peer) {
+            return false;
+        }
+        let returning = self.peers.get(peer).cloned().unwrap_or_default();
+        if self.active().len() >= self.max_peers {
+            let Some((worst, score)) = self.worst() else {
+                return false;
+            };
+            if score >= returning.score() {
+                return false;
+            }
+            println!("🧹 Evicting peer {} (score {}) for {}", worst, score, peer);
+            self.remove(&worst);
+        }
+        self.peers.insert(peer.to_string(), PeerScore { banned_until: None, ..returning });
+        true
+    }
+
+    fn worst(&self) -> Option<(String, i64)> {
+        self.peers
+            .iter()
+            .filter(|(_, score)| score.banned_until.is_none())
+            .map(|(peer, score)| (peer.clone(), score.score()))
+            .min_by_key(|(_, score)| *score)
+    }
+
+    /// Forgets `peer` without banning it.
+    pub fn remove(&mut self, peer: &str) {
+        self.peers.remove(peer);
+        self.hellos.remove(peer);
+    }
+
+    /// Keeps the hello an active peer sent us; ignored for any other peer.
+    pub fn set_hello(&mut self, peer: &str, hello: Hello) {
+        if self.contains(peer) {
+            self.hellos.insert(peer.to_string(), hello);
+        }
+    }
+
+    pub fn hello(&self, peer: &str) -> Option<&Hello> {
+        self.hellos.get(peer)
+    }
+
+    /// Notes that `peer` answered a request within `latency`.
+    pub fn record_success(&mut self, peer: &str, latency: Duration) {
+        let Some(score) = self.peers.get_mut(peer).filter(|score| score.banned_until.is_none()) else {
+            return;
+        };
+        let sample = latency.as_millis() as u64;
+        score.latency_ms = Some(score.latency_ms.map_or(sample, |avg| (avg * 7 + sample) / 8));
+        score.successes += 1;
+        score.consecutive_failures = 0;
+        score.last_seen = Some(now_millis());
+    }
+
+    /// Notes that a request to `peer` failed or timed out. Returns true if
+    /// that was one failure too many and the peer was dropped.
+    pub fn record_failure(&mut self, peer: &str) -> bool {
+        let Some(score) = self.peers.get_mut(peer).filter(|score| score.banned_until.is_none()) else {
+            return false;
+        };
+        score.failures += 1;
+        score.consecutive_failures += 1;
+        if score.consecutive_failures < MAX_CONSECUTIVE_FAILURES {
+            return false;
+        }
+        println!("🧹 Dropping unreachable peer {}", peer);
+        self.remove(peer);
+        true
+    }
+
+    /// Notes that `peer` sent an invalid block or header, and bans it.
+    pub fn record_invalid(&mut self, peer: &str) {
+        self.peers.entry(peer.to_string()).or_default().invalid_blocks += 1;
+        self.ban(peer);
+    }
+
+    /// Stops talking to `peer` and refuses it until the ban duration has
+    /// passed. Peers whose bans have already ended are forgotten.
+    pub fn ban(&mut self, peer: &str) {
+        println!("⛔ Banning peer {} for {}s", peer, self.ban_duration_ms / 1000);
+        let now = now_millis();
+        self.peers.retain(|_, score| score.banned_until.is_none_or(|until| until > now));
+        self.peers.entry(peer.to_string()).or_default().banned_until = Some(now + self.ban_duration_ms);
+        self.hellos.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Hash256;
+
+    fn manager(max_peers: usize) -> PeerManager {
+        PeerManager::new(max_peers, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn scores_reward_answers_and_punish_misbehaviour() {
+        let good = PeerScore { successes: 10, ..PeerScore::default() };
+        let flaky = PeerScore { successes: 10, consecutive_failures: 3, ..PeerScore::default() };
+        let slow = PeerScore { successes: 10, latency_ms: Some(2_000), ..PeerScore::default() };
+        let bad = PeerScore { successes: 10, invalid_blocks: 1, ..PeerScore::default() };
+        assert!(good.score() > PeerScore::default().score());
+        assert!(good.score() > flaky.score());
+        assert!(good.score() > slow.score());
+        assert!(bad.score() < 0);
+    }
+
+    #[test]
+    fn the_worst_peer_makes_room_only_for_a_better_one() {
+        let mut peers = manager(2);
+        assert!(peers.add("a"));
+        assert!(peers.add("b"));
+        assert!(!peers.add("a"));
+        // A newcomer scores zero, no better than either peer yet.
+        assert!(!peers.add("c"));
+
+        peers.record_success("a", Duration::from_millis(10));
+        for _ in 0..3 {
+            peers.record_failure("b");
+        }
+        assert!(peers.add("c"));
+        assert_eq!(peers.active(), vec!["a".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn successes_track_latency_and_failures_drop_unreachable_peers() {
+        let mut peers = manager(4);
+        peers.add("a");
+        peers.record_success("a", Duration::from_millis(800));
+        peers.record_success("a", Duration::from_millis(0));
+        let score = &peers.scores()["a"];
+        assert_eq!((score.successes, score.latency_ms), (2, Some(700)));
+        assert!(score.last_seen.is_some());
+
+        for _ in 1..MAX_CONSECUTIVE_FAILURES {
+            assert!(!peers.record_failure("a"));
+        }
+        assert!(peers.record_failure("a"));
+        assert!(!peers.contains("a"));
+        // Unknown peers are not tracked.
+        peers.record_success("b", Duration::ZERO);
+        assert!(!peers.record_failure("b"));
+        assert!(peers.scores().is_empty());
+    }
+
+    #[test]
+    fn hellos_are_kept_only_for_active_peers() {
+        let hello = |url: &str| Hello {
+            protocol_version: 1,
+            genesis: Hash256::ZERO,
+            node_id: url.to_string(),
+            best_height: 0,
+            user_agent: String::new(),
+            features: Vec::new(),
+            url: url.to_string(),
+            announcement: None,
+        };
+        let mut peers = manager(1);
+        peers.set_hello("a", hello("a"));
+        assert!(peers.hello("a").is_none());
+
+        peers.add("a");
+        peers.set_hello("a", hello("a"));
+        assert_eq!(peers.hello("a").map(|h| h.url.as_str()), Some("a"));
+        peers.record_invalid("a");
+        assert!(peers.hello("a").is_none());
+
+        peers.add("b");
+        peers.set_hello("b", hello("b"));
+        for _ in 0..3 {
+            peers.record_failure("b");
+        }
+        // Evicting "b" to make room for "c" takes its hello with it.
+        assert!(peers.add("c"));
+        assert!(peers.hello("b").is_none());
+        peers.set_hello("c", hello("c"));
+        peers.remove("c");
+        assert!(peers.hello("c").is_none());
+    }
+
+    #[test]
+    fn invalid_blocks_ban_a_peer_until_the_ban_ends() {
+        let mut peers = manager(4);
+        peers.add("a");
+        peers.record_invalid("a");
+        assert!(peers.is_banned("a"));
+        assert!(!peers.contains("a"));
+        assert!(peers.active().is_empty());
+        assert!(!peers.add("a"));
+
+        let mut lenient = PeerManager::new(4, Duration::ZERO);
+        lenient.add("a");
+        lenient.record_invalid("a");
+        assert!(!lenient.is_banned("a"));
+        // Back once the ban is over, but its record comes with it.
+        assert!(lenient.add("a"));
+        assert_eq!(lenient.scores()["a"].invalid_blocks, 1);
+        assert_eq!(lenient.scores()["a"].banned_until, None);
+    }
+}
@@ -0,0 +1,218 @@
+// === pow.rs ===
+
+use crate::blockchain::BlockHeader;
+use crate::cryptography::verify_pow;
+use crate::encoding::encode_header;
+use crate::hash::Hash256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+/// A proof-of-work function over block headers.
+///
+/// The PoW hash is separate from the block's id (`cryptography::calculate_hash`),
+/// so swapping algorithms never changes how blocks are referenced.
+pub trait PowAlgorithm: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The genesis-spec entry that selects this algorithm and its parameters.
+    fn config(&self) -> PowConfig;
+
+    /// The hash compared against the header's target.
+    fn hash(&self, header: &BlockHeader) -> Hash256;
+
+    /// Returns whether `header` meets the target encoded in its own `bits`.
+    fn verify(&self, header: &BlockHeader) -> bool {
+        verify_pow(&self.hash(header), header.bits)
+    }
+}
+
+/// Single SHA-256 of the encoded header; the PoW hash equals the block id.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Pow;
+
+impl PowAlgorithm for Sha256Pow {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn config(&self) -> PowConfig {
+        PowConfig::Sha256
+    }
+
+    fn hash(&self, header: &BlockHeader) -> Hash256 {
+        Hash256::digest(encode_header(header))
+    }
+}
+
+/// SHA-256 applied twice, as in Bitcoin.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256dPow;
+
+impl PowAlgorithm for Sha256dPow {
+    fn name(&self) -> &'static str {
+        "sha256d"
+    }
+
+    fn config(&self) -> PowConfig {
+        PowConfig::Sha256d
+    }
+
+    fn hash(&self, header: &BlockHeader) -> Hash256 {
+        Hash256::digest(Hash256::digest(encode_header(header)).as_bytes())
+    }
+}
+
+/// scrypt over the encoded header, which also serves as the salt. Every hash
+/// needs `128 * r * 2^log_n` bytes of memory, which blunts the edge of
+/// dedicated hardware.
+#[derive(Debug, Clone, Copy)]
+pub struct ScryptPow {
+    params: scrypt::Params,
+}
+
+impl ScryptPow {
+    pub fn new(log_n: u8, r: u32, p: u32) -> Result<Self, scrypt::errors::InvalidParams> {
+        Ok(ScryptPow { params: scrypt::Params::new(log_n, r, p, 32)? })
+    }
+}
+
+impl PowAlgorithm for ScryptPow {
+    fn name(&self) -> &'static str {
+        "scrypt"
+    }
+
+    fn config(&self) -> PowConfig {
+        PowConfig::Scrypt { log_n: self.params.log_n(), r: self.params.r(), p: self.params.p() }
+    }
+
+    fn hash(&self, header: &BlockHeader) -> Hash256 {
+        let encoded = encode_header(header);
+        let mut out = [0u8; 32];
+        scrypt::scrypt(&encoded, &encoded, &self.params, &mut out).expect("output length is fixed at 32");
+        Hash256(out)
+    }
+}
+
+/// Proof-of-work selection as written in the genesis spec, e.g.
+/// `{ "algorithm": "scrypt", "log_n": 10, "r": 1, "p": 1 }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum PowConfig {
+    #[default]
+    Sha256,
+    Sha256d,
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+/// Most memory one scrypt hash may need. Every node hashes every block, so a
+/// genesis spec asking for more would make validation impractically slow.
+pub const MAX_SCRYPT_MEMORY: u64 = 64 * 1024 * 1024;
+
+/// Most parallel scrypt lanes a genesis spec may ask for; each one multiplies
+/// the work of a single hash.
+pub const MAX_SCRYPT_P: u32 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPowConfig(pub String);
+
+impl fmt::Display for InvalidPowConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid proof-of-work parameters: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPowConfig {}
+
+impl PowConfig {
+    /// Builds the configured algorithm, refusing scrypt parameters beyond
+    /// `MAX_SCRYPT_MEMORY` and `MAX_SCRYPT_P`.
+    pub fn algorithm(&self) -> Result<Arc<dyn PowAlgorithm>, InvalidPowConfig> {
+        if let PowConfig::Scrypt { log_n, r, p } = *self {
+            let memory = 1u64.checked_shl(log_n as u32).and_then(|n| n.checked_mul(128 * r as u64));
+            if memory.is_none_or(|m| m > MAX_SCRYPT_MEMORY) {
+                return Err(InvalidPowConfig(format!("scrypt needs more than {} bytes per hash", MAX_SCRYPT_MEMORY)));
+            }
+            if p > MAX_SCRYPT_P {
+                return Err(InvalidPowConfig(format!("scrypt p may be at most {}", MAX_SCRYPT_P)));
+            }
+        }
+        Ok(match *self {
+            PowConfig::Sha256 => Arc::new(Sha256Pow),
+            PowConfig::Sha256d => Arc::new(Sha256dPow),
+            PowConfig::Scrypt { log_n, r, p } => {
+                Arc::new(ScryptPow::new(log_n, r, p).map_err(|e| InvalidPowConfig(e.to_string()))?)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::calculate_hash;
+    use crate::difficulty::target_to_compact;
+    use primitive_types::U256;
+
+    fn header(bits: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            index: 1,
+            timestamp: 1,
+            prev_hash: Hash256::ZERO,
+            merkle_root: Hash256::ZERO,
+            bits,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn algorithms_hash_differently() {
+        let header = header(0x207fffff);
+        let sha256 = Sha256Pow.hash(&header);
+        let sha256d = Sha256dPow.hash(&header);
+        let scrypt = ScryptPow::new(4, 1, 1).unwrap().hash(&header);
+        assert_eq!(sha256, calculate_hash(&header));
+        assert_eq!(sha256d, Hash256::digest(sha256.as_bytes()));
+        assert_ne!(scrypt, sha256);
+        assert_eq!(scrypt, ScryptPow::new(4, 1, 1).unwrap().hash(&header));
+    }
+
+    #[test]
+    fn verify_checks_the_algorithms_own_hash() {
+        // Only one of the two algorithms meets this target, which half of all hashes miss.
+        let bits = target_to_compact(U256::MAX >> 1);
+        let mut header = header(bits);
+        while Sha256Pow.verify(&header) == Sha256dPow.verify(&header) {
+            header.nonce += 1;
+        }
+        let (sha, shad) = (Sha256Pow.verify(&header), Sha256dPow.verify(&header));
+        assert_eq!(sha, Sha256Pow.hash(&header).to_u256() <= U256::MAX >> 1);
+        assert_eq!(shad, !sha);
+    }
+
+    #[test]
+    fn configs_parse_from_the_genesis_format() {
+        let config: PowConfig = serde_json::from_str(r#"{ "algorithm": "scrypt", "log_n": 10, "r": 1, "p": 1 }"#).unwrap();
+        assert_eq!(config, PowConfig::Scrypt { log_n: 10, r: 1, p: 1 });
+        assert_eq!(config.algorithm().unwrap().name(), "scrypt");
+        assert_eq!(serde_json::from_str::<PowConfig>(r#"{ "algorithm": "sha256d" }"#).unwrap(), PowConfig::Sha256d);
+        assert!(serde_json::from_str::<PowConfig>(r#"{ "algorithm": "x11" }"#).is_err());
+    }
+
+    #[test]
+    fn algorithms_report_the_config_they_were_built_from() {
+        for config in [PowConfig::Sha256, PowConfig::Sha256d, PowConfig::Scrypt { log_n: 4, r: 2, p: 3 }] {
+            assert_eq!(config.algorithm().unwrap().config(), config);
+        }
+    }
+
+    #[test]
+    fn scrypt_parameters_are_bounded() {
+        assert!(PowConfig::Scrypt { log_n: 14, r: 8, p: 1 }.algorithm().is_ok());
+        assert!(PowConfig::Scrypt { log_n: 20, r: 8, p: 1 }.algorithm().is_err());
+        assert!(PowConfig::Scrypt { log_n: 63, r: 1, p: 1 }.algorithm().is_err());
+        assert!(PowConfig::Scrypt { log_n: 10, r: u32::MAX, p: 1 }.algorithm().is_err());
+        assert!(PowConfig::Scrypt { log_n: 10, r: 1, p: MAX_SCRYPT_P + 1 }.algorithm().is_err());
+    }
+}
@@ -7,6 +7,8 @@ pub fn prune_chain(chain: &mut Blockchain, retain_count: usize) {
     if chain.blocks.len() > retain_count {
         let drop_count = chain.blocks.len() - retain_count;
         chain.blocks.drain(0..drop_count);
+        let first_index = chain.tip().header.index + 1 - retain_count as u64;
+        chain.forget_below(first_index);
         println!("🧹 Pruned {} blocks, {} remain", drop_count, chain.blocks.len());
     }
 }
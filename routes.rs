@@ -1,19 +1,46 @@
 // === routes.rs ===
 
-use crate::blockchain::Blockchain;
-use crate::networking::{broadcast_block, get_peers, register_peer};
+use crate::blockchain::{Block, BlockStatus, Blockchain};
+use crate::handshake::{HandshakeReply, Hello};
+use crate::hash::Hash256;
+use crate::identity::PeerAnnouncement;
+use crate::merkle::merkle_proof;
+use crate::jobs::MiningJobs;
+use crate::networking::{
+    broadcast_block, fetch_missing_parents, get_peers, is_known_peer, my_url, peer_hello, peer_scores, receive_announcement,
+    register_peer, PEER_HEADER,
+};
 use crate::prune::prune_chain;
+use crate::sync::{progress, MAX_HEADERS};
+use crate::transaction::Transaction;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::env;
 use std::sync::{Arc, Mutex};
+use tokio::task;
 use warp::Filter;
 
+/// Most blocks a single `/blocks` range query returns.
+const MAX_BLOCK_RANGE: u64 = 100;
+
+/// Largest block body `/submit_block` accepts, in bytes.
+const MAX_BLOCK_BODY: u64 = 2 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct RangeQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
 pub fn build_routes(
     chain: Arc<Mutex<Blockchain>>,
+    jobs: Arc<MiningJobs>,
     rate_limiter: impl Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    let chain_filter = warp::any().map(move || chain.clone());
     let chain_status = chain.clone();
+    let genesis_hash = chain.lock().unwrap().genesis_hash;
+    let chain_filter = warp::any().map(move || chain.clone());
+    let jobs_filter = warp::any().map(move || jobs.clone());
 
     let api_key: &'static str = Box::leak(Box::new(env::var("API_KEY").unwrap_or_else(|_| "secretkey".into())));
     let protected = warp::header::exact("x-api-key", api_key);
@@ -21,46 +48,89 @@ pub fn build_routes(
     let status = warp::path("status").map(move || {
         let c = chain_status.lock().unwrap();
         let tip = c.tip();
-        warp::reply::json(&serde_json::json!({ "index": tip.index, "hash": tip.hash }))
+        warp::reply::json(&serde_json::json!({ "index": tip.header.index, "hash": tip.hash }))
     });
 
     let tip = warp::path("tip").and(chain_filter.clone()).map(|chain: Arc<Mutex<Blockchain>>| {
         let c = chain.lock().unwrap();
-        warp::reply::json(&*c.tip())
+        warp::reply::json(c.tip())
     });
 
     let peers = warp::path("peers").and(warp::get()).map(|| {
         warp::reply::json(&get_peers())
     });
 
+    let scores = warp::path!("peers" / "scores").and(warp::get()).map(|| warp::reply::json(&peer_scores()));
+
+    let peer_info = warp::path!("peers" / "info").and(warp::get()).map(|| {
+        let info: HashMap<String, Hello> = get_peers().into_iter().filter_map(|peer| Some((peer.clone(), peer_hello(&peer)?))).collect();
+        warp::reply::json(&info)
+    });
+
     let add_peer = warp::path("add_peer")
         .and(warp::post())
         .and(warp::body::json())
-        .map(|peer: String| {
-            let added = register_peer(peer);
-            warp::reply::json(&serde_json::json!({ "added": added }))
+        .and(chain_filter.clone())
+        .and_then(|peer: String, chain: Arc<Mutex<Blockchain>>| async move {
+            let added = register_peer(peer, chain).await;
+            Ok::<_, warp::Rejection>(warp::reply::json(&serde_json::json!({ "added": added })))
         });
 
+    // Signed peer records relayed by other nodes; see `receive_announcement`.
+    let announce = warp::path("announce")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(chain_filter.clone())
+        .and_then(|announcement: PeerAnnouncement, chain: Arc<Mutex<Blockchain>>| async move {
+            let reply = match receive_announcement(announcement, chain).await {
+                Ok(added) => serde_json::json!({ "added": added }),
+                Err(e) => serde_json::json!({ "added": false, "error": e.to_string() }),
+            };
+            Ok::<_, warp::Rejection>(warp::reply::json(&reply))
+        });
+
+    // Answers a node that wants to become our peer with our own hello, or
+    // with why the two of us can't talk.
+    let handshake = warp::path("handshake")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(chain_filter.clone())
+        .and_then(|theirs: Hello, chain: Arc<Mutex<Blockchain>>| async move {
+            let url = my_url().await;
+            let ours = Hello::ours(&chain.lock().unwrap(), url);
+            let reply = match theirs.check(&ours) {
+                Ok(()) => HandshakeReply::Accepted(Box::new(ours)),
+                Err(e) => HandshakeReply::Refused(e.to_string()),
+            };
+            Ok::<_, warp::Rejection>(warp::reply::json(&reply))
+        });
+
+    let genesis = warp::path("genesis").and(warp::get()).map(move || {
+        warp::reply::json(&serde_json::json!({ "hash": genesis_hash }))
+    });
+
     let summary = warp::path!("chain" / "summary")
         .and(chain_filter.clone())
         .map(|chain: Arc<Mutex<Blockchain>>| {
             let c = chain.lock().unwrap();
             warp::reply::json(&serde_json::json!({
                 "length": c.blocks.len(),
-                "tip_index": c.tip().index,
-                "tip_hash": c.tip().hash
+                "tip_index": c.tip().header.index,
+                "tip_hash": c.tip().hash,
+                "orphans": c.orphans.len()
             }))
         });
 
-    let block_lookup = warp::path!("block" / String)
+    let block_lookup = warp::path!("block" / Hash256)
         .and(warp::query::<HashMap<String, String>>())
         .and(chain_filter.clone())
-        .map(|hash: String, params: HashMap<String, String>, chain: Arc<Mutex<Blockchain>>| {
+        .map(|hash: Hash256, params: HashMap<String, String>, chain: Arc<Mutex<Blockchain>>| {
             let c = chain.lock().unwrap();
-            let filtered = if let Some(f) = params.get("contains") {
-                c.blocks.iter().find(|b| b.hash == hash && b.data.contains(f))
-            } else {
-                c.blocks.iter().find(|b| b.hash == hash)
+            let filtered = match params.get("address") {
+                Some(addr) => c.canonical_block(&hash).filter(|b| b.transactions.iter().any(|tx| tx.involves(addr))),
+                None => c.canonical_block(&hash),
             };
             match filtered {
                 Some(b) => warp::reply::json(b),
@@ -68,19 +138,198 @@ pub fn build_routes(
             }
         });
 
-    let mine = warp::path("mine")
+    let block_by_height = warp::path!("block" / "height" / u64)
+        .and(chain_filter.clone())
+        .map(|height: u64, chain: Arc<Mutex<Blockchain>>| {
+            let c = chain.lock().unwrap();
+            match c.block_at_height(height) {
+                Some(b) => warp::reply::json(b),
+                None => warp::reply::json(&serde_json::json!({ "error": "No block at that height" })),
+            }
+        });
+
+    let block_range = warp::path("blocks")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<RangeQuery>())
+        .and(chain_filter.clone())
+        .map(|range: RangeQuery, chain: Arc<Mutex<Blockchain>>| {
+            let c = chain.lock().unwrap();
+            let to = range.to.unwrap_or(c.tip().header.index);
+            let (from, to) = match range.from {
+                Some(from) => (from, to.min(from.saturating_add(MAX_BLOCK_RANGE - 1))),
+                None => (to.saturating_sub(MAX_BLOCK_RANGE - 1), to),
+            };
+            warp::reply::json(&c.blocks_in_range(from, to))
+        });
+
+    let header_lookup = warp::path!("header" / Hash256)
+        .and(chain_filter.clone())
+        .map(|hash: Hash256, chain: Arc<Mutex<Blockchain>>| {
+            let c = chain.lock().unwrap();
+            match c.get_block(&hash) {
+                Some(b) => warp::reply::json(&serde_json::json!({ "header": b.header, "hash": b.hash })),
+                None => warp::reply::json(&serde_json::json!({ "error": "Block not found" })),
+            }
+        });
+
+    // Headers-first sync: the canonical headers after the first hash of the
+    // comma-separated `locator` that we share with the caller.
+    let headers = warp::path("headers")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(chain_filter.clone())
+        .map(|params: HashMap<String, String>, chain: Arc<Mutex<Blockchain>>| {
+            let locator: Result<Vec<Hash256>, _> = params
+                .get("locator")
+                .map(String::as_str)
+                .unwrap_or_default()
+                .split(',')
+                .filter(|hash| !hash.is_empty())
+                .map(str::parse)
+                .collect();
+            match locator {
+                Ok(locator) => warp::reply::json(&chain.lock().unwrap().headers_after(&locator, MAX_HEADERS)),
+                Err(e) => warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+            }
+        });
+
+    let sync_status = warp::path("sync").and(warp::get()).map(|| warp::reply::json(&progress()));
+
+    let tx_proof = warp::path!("block" / Hash256 / "proof" / Hash256)
+        .and(chain_filter.clone())
+        .map(|hash: Hash256, tx_id: Hash256, chain: Arc<Mutex<Blockchain>>| {
+            let c = chain.lock().unwrap();
+            let Some(block) = c.get_block(&hash) else {
+                return warp::reply::json(&serde_json::json!({ "error": "Block not found" }));
+            };
+            let leaves = block.tx_ids();
+            match leaves.iter().position(|id| *id == tx_id).and_then(|i| merkle_proof(&leaves, i)) {
+                Some(proof) => warp::reply::json(&serde_json::json!({
+                    "block_hash": block.hash,
+                    "merkle_root": block.header.merkle_root,
+                    "tx_id": tx_id,
+                    "proof": proof
+                })),
+                None => warp::reply::json(&serde_json::json!({ "error": "Transaction not in block" })),
+            }
+        });
+
+    let balance = warp::path!("balance" / String)
+        .and(chain_filter.clone())
+        .map(|address: String, chain: Arc<Mutex<Blockchain>>| {
+            let c = chain.lock().unwrap();
+            let account = c.account(&address);
+            warp::reply::json(&serde_json::json!({
+                "address": address,
+                "balance": account.balance,
+                "nonce": account.nonce
+            }))
+        });
+
+    let submit_tx = warp::path("tx")
         .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
         .and(warp::body::json())
         .and(chain_filter.clone())
-        .map(|data: String, chain: Arc<Mutex<Blockchain>>| {
-            if data.trim().is_empty() || data.len() > 1024 {
-                return warp::reply::json(&serde_json::json!({ "error": "Invalid data payload" }));
-            }
+        .map(|tx: Transaction, chain: Arc<Mutex<Blockchain>>| {
             let mut c = chain.lock().unwrap();
-            let block = c.mine_block(data);
-            broadcast_block(&block);
-            let added = c.add_block(block.clone());
-            warp::reply::json(&serde_json::json!({ "added": added, "hash": block.hash }))
+            match c.submit_transaction(tx) {
+                Ok(id) => warp::reply::json(&serde_json::json!({ "accepted": true, "id": id })),
+                Err(e) => warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+            }
+        });
+
+    let template = warp::path("template")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(chain_filter.clone())
+        .map(|params: HashMap<String, String>, chain: Arc<Mutex<Blockchain>>| {
+            let c = chain.lock().unwrap();
+            let address = params.get("address").map(String::as_str).unwrap_or_default();
+            warp::reply::json(&c.block_template(address))
+        });
+
+    let submit_block = warp::path("submit_block")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BLOCK_BODY))
+        .and(warp::body::json())
+        .and(chain_filter.clone())
+        .and_then(|block: Block, chain: Arc<Mutex<Blockchain>>| async move {
+            // Validation may replay a reorg, so it runs off the runtime too.
+            let candidate = block.clone();
+            let status = task::spawn_blocking(move || chain.lock().unwrap().add_block(candidate)).await;
+            let reply = match status {
+                Ok(Ok(BlockStatus::Extended | BlockStatus::Reorg(_))) => {
+                    broadcast_block(&block, None);
+                    warp::reply::json(&serde_json::json!({ "accepted": true, "hash": block.hash }))
+                }
+                Ok(Ok(_)) => warp::reply::json(&serde_json::json!({ "accepted": false, "error": "block does not extend the tip" })),
+                Ok(Err(e)) => warp::reply::json(&serde_json::json!({ "accepted": false, "error": e.to_string() })),
+                Err(e) => warp::reply::json(&serde_json::json!({ "accepted": false, "error": e.to_string() })),
+            };
+            Ok::<_, warp::Rejection>(reply)
+        });
+
+    // Blocks gossiped by peers. Only blocks that move our tip are relayed on;
+    // for an orphan, the missing ancestors are fetched from the sender.
+    // Anyone can claim to be a peer here, so an invalid block is refused but
+    // never held against the named sender.
+    let receive_block = warp::path("block")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BLOCK_BODY))
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>(PEER_HEADER))
+        .and(chain_filter.clone())
+        .and_then(|block: Block, sender: Option<String>, chain: Arc<Mutex<Blockchain>>| async move {
+            // Validation may replay a reorg, so it runs off the runtime too.
+            let (candidate, shared) = (block.clone(), chain.clone());
+            let status = task::spawn_blocking(move || shared.lock().unwrap().add_block(candidate)).await;
+            let reply = match status {
+                Ok(Ok(BlockStatus::Extended | BlockStatus::Reorg(_))) => {
+                    broadcast_block(&block, sender.as_deref());
+                    warp::reply::json(&serde_json::json!({ "accepted": true }))
+                }
+                Ok(Ok(BlockStatus::SideBranch)) => warp::reply::json(&serde_json::json!({ "accepted": true })),
+                Ok(Ok(BlockStatus::Orphan { missing })) => {
+                    if let Some(peer) = sender.filter(|peer| is_known_peer(peer)) {
+                        tokio::spawn(async move { fetch_missing_parents(&chain, &peer, missing).await });
+                    }
+                    warp::reply::json(&serde_json::json!({ "accepted": true, "orphan": true }))
+                }
+                Ok(Err(e)) => warp::reply::json(&serde_json::json!({ "accepted": false, "error": e.to_string() })),
+                Err(e) => warp::reply::json(&serde_json::json!({ "accepted": false, "error": e.to_string() })),
+            };
+            Ok::<_, warp::Rejection>(reply)
+        });
+
+    let mempool = warp::path("mempool").and(warp::get()).and(chain_filter.clone()).map(|chain: Arc<Mutex<Blockchain>>| {
+        let c = chain.lock().unwrap();
+        warp::reply::json(&serde_json::json!({ "count": c.mempool.len() }))
+    });
+
+    let mine = warp::path("mine")
+        .and(warp::post())
+        .and(jobs_filter.clone())
+        .map(|jobs: Arc<MiningJobs>| match jobs.submit() {
+            Ok(id) => warp::reply::json(&serde_json::json!({ "job": id })),
+            Err(e) => warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+        });
+
+    let job_status = warp::path!("jobs" / u64)
+        .and(warp::get())
+        .and(jobs_filter.clone())
+        .map(|id: u64, jobs: Arc<MiningJobs>| match jobs.status(id) {
+            Some(status) => warp::reply::json(&status),
+            None => warp::reply::json(&serde_json::json!({ "error": "Unknown job" })),
+        });
+
+    let cancel_job = warp::path!("jobs" / u64)
+        .and(warp::delete())
+        .and(jobs_filter.clone())
+        .map(|id: u64, jobs: Arc<MiningJobs>| {
+            warp::reply::json(&serde_json::json!({ "cancelled": jobs.cancel(id) }))
         });
 
     let prune = warp::path("prune")
@@ -106,18 +355,52 @@ pub fn build_routes(
         warp::reply::json(&serde_json::json!({ "note": "Handled in-memory or Redis via rate_limit.rs" }))
     });
 
-    let secured_mine = protected.clone().and(rate_limiter.clone()).and(mine);
+    let secured_mine = protected.and(rate_limiter.clone()).and(mine);
+    let limited_submit_tx = rate_limiter.clone().and(submit_tx);
+    let limited_submit_block = rate_limiter.clone().and(submit_block);
+    let limited_receive_block = rate_limiter.clone().and(receive_block);
+    // Anyone may call these, and `/add_peer` makes us dial whatever URL it names.
+    let limited_add_peer = rate_limiter.clone().and(add_peer);
+    let limited_handshake = rate_limiter.clone().and(handshake);
+    let limited_announce = rate_limiter.clone().and(announce);
+    let secured_cancel_job = protected.and(cancel_job);
     let secured_prune = protected.and(prune);
 
-    status
+    // Boxed in groups so the type of the whole `or` chain stays shallow
+    // enough for the compiler's default recursion limit.
+    let chain_routes = status
         .or(tip)
+        .or(scores)
+        .or(peer_info)
         .or(peers)
-        .or(add_peer)
+        .or(limited_add_peer)
+        .or(limited_handshake)
+        .or(limited_announce)
+        .or(genesis)
         .or(summary)
+        .or(block_by_height)
+        .or(block_range)
         .or(block_lookup)
+        .or(header_lookup)
+        .or(headers)
+        .or(sync_status)
+        .boxed();
+    let block_routes = tx_proof
+        .or(balance)
+        .or(limited_submit_tx)
+        .or(template)
+        .or(limited_submit_block)
+        .or(limited_receive_block)
+        .or(mempool)
         .or(secured_mine)
+        .boxed();
+    let admin_routes = job_status
+        .or(secured_cancel_job)
         .or(secured_prune)
         .or(health_check)
         .or(redis_health)
         .or(rate_stats)
+        .boxed();
+
+    chain_routes.or(block_routes).or(admin_routes)
 }
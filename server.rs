@@ -1,28 +1,42 @@
 
 use crate::blockchain::Blockchain;
+use crate::genesis::GenesisSpec;
+use crate::identity::load_identity;
+use crate::jobs::MiningJobs;
+use crate::mining::Miner;
+use crate::networking;
 use crate::rate_limit::rate_limited;
 use crate::routes::build_routes;
 use crate::storage::{load_chain, save_chain};
+use crate::sync;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::task;
 use warp::Filter;
 
 pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let chain = Arc::new(Mutex::new(load_chain().unwrap_or_else(Blockchain::new)));
+    let genesis = GenesisSpec::from_env()?;
+    let chain = Arc::new(Mutex::new(load_chain(&genesis).unwrap_or_else(|| Blockchain::from_genesis(&genesis))));
+    println!("🌐 Network {} (genesis {})", genesis.chain_id, chain.lock().unwrap().genesis_hash);
+    println!("🪪 Node id {}", load_identity()?.node_id());
     let chain_status = chain.clone();
-    let chain_for_filter = chain.clone();
 
-    let routes = build_routes(chain_status, rate_limited().map(|_| ())).with(warp::log::custom(|info| {
+    let jobs = Arc::new(MiningJobs::new(Miner::from_env(chain.clone())));
+
+    let routes = build_routes(chain_status, jobs, rate_limited().untuple_one()).with(warp::log::custom(|info| {
         println!("📥 {} {} {}", info.method(), info.path(), info.status());
     }));
 
+    networking::start_outbound_queue();
+    sync::start(chain.clone());
+
     let chain_clone = chain.clone();
     task::spawn(async move {
         loop {
             tokio::time::sleep(Duration::from_secs(10)).await;
             let c = chain_clone.lock().unwrap();
-            save_chain(&*c);
+            save_chain(&c);
+            networking::save_peers();
         }
     });
 
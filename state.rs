@@ -0,0 +1,211 @@
+// === state.rs ===
+
+use crate::blockchain::Block;
+use crate::hash::Hash256;
+use crate::transaction::Transaction;
+use crate::validation::BlockError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: u64,
+    /// Nonce the account's next transaction must carry.
+    pub nonce: u64,
+}
+
+/// Why a transaction can't be applied on top of the current state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    InsufficientBalance { from: String, balance: u64, needed: u64 },
+    BadNonce { from: String, expected: u64, found: u64 },
+    AmountOverflow,
+    BalanceOverflow { to: String },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InsufficientBalance { from, balance, needed } => write!(f, "{} has {} but spends {}", from, balance, needed),
+            StateError::BadNonce { from, expected, found } => write!(f, "{} nonce {} is not the expected {}", from, found, expected),
+            StateError::AmountOverflow => write!(f, "amounts plus fee overflow"),
+            StateError::BalanceOverflow { to } => write!(f, "balance of {} overflows", to),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Prior values of every account a block touched, in the order it touched them.
+type Journal = Vec<(String, Option<Account>)>;
+
+/// Account balances and nonces obtained by replaying the canonical chain.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    pub accounts: HashMap<String, Account>,
+    /// Journals of applied blocks, keyed by block hash, so reorgs can roll back.
+    undo: HashMap<Hash256, Journal>,
+}
+
+impl State {
+    pub fn account(&self, address: &str) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    fn set(&mut self, address: &str, account: Account, journal: &mut Journal) {
+        journal.push((address.to_string(), self.accounts.get(address).copied()));
+        self.accounts.insert(address.to_string(), account);
+    }
+
+    fn apply_transaction(&mut self, tx: &Transaction, journal: &mut Journal) -> Result<(), StateError> {
+        // A coinbase mints its outputs; validation has already capped the amount.
+        if !tx.is_coinbase() {
+            let total = tx.total_cost().ok_or(StateError::AmountOverflow)?;
+            let mut sender = self.account(&tx.from);
+            if tx.nonce != sender.nonce {
+                return Err(StateError::BadNonce { from: tx.from.clone(), expected: sender.nonce, found: tx.nonce });
+            }
+            if sender.balance < total {
+                return Err(StateError::InsufficientBalance { from: tx.from.clone(), balance: sender.balance, needed: total });
+            }
+            sender.balance -= total;
+            sender.nonce += 1;
+            self.set(&tx.from, sender, journal);
+        }
+
+        for output in &tx.outputs {
+            let mut recipient = self.account(&output.to);
+            recipient.balance = recipient
+                .balance
+                .checked_add(output.amount)
+                .ok_or_else(|| StateError::BalanceOverflow { to: output.to.clone() })?;
+            self.set(&output.to, recipient, journal);
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self, journal: Journal) {
+        for (address, prior) in journal.into_iter().rev() {
+            match prior {
+                Some(account) => self.accounts.insert(address, account),
+                None => self.accounts.remove(&address),
+            };
+        }
+    }
+
+    /// Applies every transaction in `block`, or none of them if any fails.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), BlockError> {
+        let mut journal = Journal::new();
+        for (position, tx) in block.transactions.iter().enumerate() {
+            if let Err(reason) = self.apply_transaction(tx, &mut journal) {
+                self.rollback(journal);
+                return Err(BlockError::InvalidState { position, reason });
+            }
+        }
+        self.undo.insert(block.hash, journal);
+        Ok(())
+    }
+
+    /// Undoes a previously applied block. Returns false if no journal is kept for it.
+    pub fn revert_block(&mut self, hash: &Hash256) -> bool {
+        match self.undo.remove(hash) {
+            Some(journal) => {
+                self.rollback(journal);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the journal of a block that can no longer be reorganized away.
+    pub fn forget(&mut self, hash: &Hash256) {
+        self.undo.remove(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difficulty::DEFAULT_BITS;
+    use crate::transaction::TxOutput;
+    use ed25519_dalek::SigningKey;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn address(key: &SigningKey) -> String {
+        hex::encode(key.verifying_key().as_bytes())
+    }
+
+    fn transfer(from: &SigningKey, to: &SigningKey, amount: u64, nonce: u64) -> Transaction {
+        Transaction::new_signed(from, vec![TxOutput { to: address(to), amount }], 0, nonce)
+    }
+
+    fn block(transactions: Vec<Transaction>) -> Block {
+        Block::new(1, 1, Hash256::ZERO, transactions, DEFAULT_BITS, 0)
+    }
+
+    fn funded(key: &SigningKey, balance: u64) -> State {
+        let mut state = State::default();
+        state.accounts.insert(address(key), Account { balance, nonce: 0 });
+        state
+    }
+
+    #[test]
+    fn applies_transfers_and_bumps_nonces() {
+        let (alice, bob) = (key(1), key(2));
+        let mut state = funded(&alice, 100);
+        state.apply_block(&block(vec![transfer(&alice, &bob, 30, 0), transfer(&alice, &bob, 20, 1)])).unwrap();
+        assert_eq!(state.account(&address(&alice)), Account { balance: 50, nonce: 2 });
+        assert_eq!(state.account(&address(&bob)), Account { balance: 50, nonce: 0 });
+    }
+
+    #[test]
+    fn coinbases_mint_without_a_sender() {
+        let (alice, miner) = (key(1), key(3));
+        let mut state = funded(&alice, 100);
+        let coinbase = Transaction::coinbase(1, &address(&miner), 50);
+        state.apply_block(&block(vec![coinbase, transfer(&alice, &miner, 30, 0)])).unwrap();
+        assert_eq!(state.account(&address(&miner)), Account { balance: 80, nonce: 0 });
+        assert_eq!(state.account(""), Account::default());
+    }
+
+    #[test]
+    fn failed_blocks_leave_no_trace() {
+        let (alice, bob) = (key(1), key(2));
+        let mut state = funded(&alice, 100);
+        let overspend = block(vec![transfer(&alice, &bob, 60, 0), transfer(&alice, &bob, 60, 1)]);
+        assert!(matches!(
+            state.apply_block(&overspend),
+            Err(BlockError::InvalidState { position: 1, reason: StateError::InsufficientBalance { .. } })
+        ));
+        assert_eq!(state.account(&address(&alice)), Account { balance: 100, nonce: 0 });
+        assert!(!state.accounts.contains_key(&address(&bob)));
+    }
+
+    #[test]
+    fn rejects_replayed_nonces() {
+        let (alice, bob) = (key(1), key(2));
+        let mut state = funded(&alice, 100);
+        let tx = transfer(&alice, &bob, 10, 0);
+        state.apply_block(&block(vec![tx.clone()])).unwrap();
+        assert!(matches!(
+            state.apply_block(&block(vec![tx])),
+            Err(BlockError::InvalidState { reason: StateError::BadNonce { expected: 1, found: 0, .. }, .. })
+        ));
+    }
+
+    #[test]
+    fn reverting_restores_the_prior_state() {
+        let (alice, bob) = (key(1), key(2));
+        let mut state = funded(&alice, 100);
+        let before = state.accounts.clone();
+        let applied = block(vec![transfer(&alice, &bob, 30, 0), transfer(&alice, &alice, 5, 1)]);
+        state.apply_block(&applied).unwrap();
+        assert!(state.revert_block(&applied.hash));
+        assert_eq!(state.accounts, before);
+        assert!(!state.revert_block(&applied.hash));
+    }
+}
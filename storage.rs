@@ -1,44 +1,77 @@
-use crate::blockchain::Blockchain;
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::Path;
-
-const PEER_FILE: &str = "peers.txt";
-const CHAIN_FILE: &str = "chain.json";
-
-pub fn save_peers(peers: &[String]) {
-    if let Ok(mut file) = File::create(PEER_FILE) {
-        for peer in peers {
-            let _ = writeln!(file, "{}", peer);
-        }
-    }
-}
-
-pub fn load_peers() -> Vec<String> {
-    let mut content = String::new();
-    if let Ok(mut file) = File::open(PEER_FILE) {
-        if file.read_to_string(&mut content).is_ok() {
-            return content
-                .lines()
-                .map(|s| s.trim().to_string())
-                .filter(|s| !s.is_empty())
-                .collect();
-        }
-    }
-    Vec::new()
-}
-
-pub fn save_chain(chain: &Blockchain) {
-    if let Ok(json) = serde_json::to_string_pretty(chain) {
-        let _ = fs::write(CHAIN_FILE, json);
-    }
-}
-
-pub fn load_chain() -> Option<Blockchain> {
-    if let Ok(content) = fs::read_to_string(CHAIN_FILE) {
-        if let Ok(chain) = serde_json::from_str(&content) {
-            return Some(chain);
-        }
-    }
-    None
-}
+use crate::blockchain::Blockchain;
+use crate::checkpoints::Checkpoints;
+use crate::genesis::GenesisSpec;
+use crate::peers::PeerScore;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+const PEER_FILE: &str = "peers.txt";
+const PEER_SCORES_FILE: &str = "peer_scores.json";
+const CHAIN_FILE: &str = "chain.json";
+
+pub fn save_peers(peers: &[String]) {
+    if let Ok(mut file) = File::create(PEER_FILE) {
+        for peer in peers {
+            let _ = writeln!(file, "{}", peer);
+        }
+    }
+}
+
+pub fn load_peers() -> Vec<String> {
+    let mut content = String::new();
+    if let Ok(mut file) = File::open(PEER_FILE)
+        && file.read_to_string(&mut content).is_ok()
+    {
+        return content
+            .lines()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+    }
+    Vec::new()
+}
+
+/// Writes every peer's score, banned peers included, next to the peer list.
+pub fn save_peer_scores(scores: &BTreeMap<String, PeerScore>) {
+    if let Ok(json) = serde_json::to_string_pretty(scores) {
+        let _ = fs::write(PEER_SCORES_FILE, json);
+    }
+}
+
+pub fn load_peer_scores() -> BTreeMap<String, PeerScore> {
+    fs::read_to_string(PEER_SCORES_FILE)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_chain(chain: &Blockchain) {
+    if let Ok(json) = serde_json::to_string_pretty(chain) {
+        let _ = fs::write(CHAIN_FILE, json);
+    }
+}
+
+/// Loads the saved chain, provided it belongs to the network described by `genesis`.
+/// Blocks below the last checkpoint are only checked for integrity, not revalidated.
+pub fn load_chain(genesis: &GenesisSpec) -> Option<Blockchain> {
+    if let Ok(content) = fs::read_to_string(CHAIN_FILE)
+        && let Ok(mut chain) = serde_json::from_str::<Blockchain>(&content)
+    {
+        let saved_genesis = chain.genesis_hash;
+        chain.genesis_hash = genesis.hash();
+        chain.params = genesis.chain_params();
+        chain.checkpoints = Checkpoints::from_config(genesis);
+        if let Err(e) = chain.check_genesis(saved_genesis, &chain.blocks).and_then(|_| chain.validate()) {
+            println!("❌ Ignoring {}: {}", CHAIN_FILE, e);
+            return None;
+        }
+        if let Err(e) = chain.rebuild_state() {
+            println!("❌ Ignoring {}: {}", CHAIN_FILE, e);
+            return None;
+        }
+        chain.rebuild_index();
+        return Some(chain);
+    }
+    None
+}
@@ -0,0 +1,312 @@
+// === sync.rs ===
+
+use crate::blockchain::{Block, BlockHeader, Blockchain};
+use crate::cryptography::calculate_hash;
+use crate::hash::Hash256;
+use crate::networking::{fetch_blocks, fetch_headers, fetch_tip, get_peers, greet_peers, query_peers, report_invalid};
+use crate::validation::ChainError;
+use lazy_static::lazy_static;
+use primitive_types::U256;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task;
+
+/// Most headers a peer sends in answer to one locator.
+pub const MAX_HEADERS: usize = 2000;
+
+/// Blocks fetched from a peer per request; matches the `/blocks` range limit.
+const BODY_BATCH: usize = 100;
+
+/// Pause between checks for a peer ahead of us.
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How far along the current (or last) sync is, as reported by `GET /sync`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncProgress {
+    pub syncing: bool,
+    /// The peer headers are downloaded from.
+    pub peer: Option<String>,
+    /// The height that peer reported for its tip.
+    pub target_height: u64,
+    pub headers: usize,
+    pub blocks: usize,
+}
+
+lazy_static! {
+    static ref PROGRESS: Mutex<SyncProgress> = Mutex::new(SyncProgress::default());
+}
+
+/// Set while a sync runs, so a sync started by a new peer doesn't race the periodic one.
+static SYNCING: AtomicBool = AtomicBool::new(false);
+
+pub fn progress() -> SyncProgress {
+    PROGRESS.lock().unwrap().clone()
+}
+
+fn update(f: impl FnOnce(&mut SyncProgress)) {
+    f(&mut PROGRESS.lock().unwrap());
+}
+
+/// Keeps the chain caught up with the network on a background task: shakes
+/// hands with the persisted peers and syncs straight away, so a freshly
+/// started node catches up, then syncs again every `SYNC_INTERVAL`.
+pub fn start(chain: Arc<Mutex<Blockchain>>) {
+    tokio::spawn(async move {
+        greet_peers(&chain).await;
+        loop {
+            sync_with_peers(&chain).await;
+            tokio::time::sleep(SYNC_INTERVAL).await;
+        }
+    });
+}
+
+/// Syncs headers-first from the known peer with the highest tip, if it is
+/// ahead of us.
+///
+/// Headers are downloaded in batches of up to `MAX_HEADERS` and the whole run
+/// is validated before any body is requested. Only if it carries more work
+/// than our tip are the bodies fetched, spread over every peer that is ahead,
+/// and connected in order. Returns whether our tip changed; false straight
+/// away if another sync is already running.
+pub async fn sync_with_peers(chain: &Arc<Mutex<Blockchain>>) -> bool {
+    let Some(_guard) = SyncGuard::acquire() else {
+        return false;
+    };
+    sync_from_best_peer(chain).await
+}
+
+/// Holds `SYNCING` for as long as it lives, so the flag is cleared however
+/// the sync ends, including when its task panics or is dropped.
+struct SyncGuard;
+
+impl SyncGuard {
+    fn acquire() -> Option<Self> {
+        (!SYNCING.swap(true, Ordering::AcqRel)).then_some(SyncGuard)
+    }
+}
+
+impl Drop for SyncGuard {
+    fn drop(&mut self) {
+        SYNCING.store(false, Ordering::Release);
+    }
+}
+
+async fn sync_from_best_peer(chain: &Arc<Mutex<Blockchain>>) -> bool {
+    let our_height = chain.lock().unwrap().tip().header.index;
+    let mut ahead: Vec<(String, u64)> = query_peers(get_peers(), |peer| async move { fetch_tip(&peer).await })
+        .await
+        .into_iter()
+        .map(|(peer, (height, _))| (peer, height))
+        .filter(|(_, height)| *height > our_height)
+        .collect();
+    if ahead.is_empty() {
+        return false;
+    }
+    ahead.sort_by_key(|(_, height)| std::cmp::Reverse(*height));
+    let (best, target_height) = ahead[0].clone();
+    println!("🔄 Syncing from {}: height {} -> {}", best, our_height, target_height);
+    update(|p| *p = SyncProgress { syncing: true, peer: Some(best.clone()), target_height, ..SyncProgress::default() });
+
+    let old_tip = chain.lock().unwrap().tip().hash;
+    if let Some(headers) = download_headers(chain, &best, target_height).await {
+        let sources: Vec<String> = ahead.into_iter().map(|(peer, _)| peer).collect();
+        download_blocks(chain, &headers, &sources).await;
+    }
+    update(|p| p.syncing = false);
+    chain.lock().unwrap().tip().hash != old_tip
+}
+
+/// Downloads `peer`'s headers past our chain up to `target_height`, the tip
+/// height it reported, and checks that they form a valid branch heavier than
+/// our tip. Each batch is validated as it arrives, so a peer feeding us bad
+/// headers is dropped after one batch, and headers past `target_height` are
+/// never kept, so a peer can't make us hold an endless run.
+async fn download_headers(chain: &Arc<Mutex<Blockchain>>, peer: &str, target_height: u64) -> Option<Vec<BlockHeader>> {
+    let mut locator = chain.lock().unwrap().locator();
+    let mut headers: Vec<BlockHeader> = Vec::new();
+    let mut work = U256::zero();
+    loop {
+        let Some(mut batch) = fetch_headers(peer, &locator).await else {
+            println!("⚠️ Could not fetch headers from {}", peer);
+            return None;
+        };
+        let full = batch.len() >= MAX_HEADERS;
+        batch.retain(|header| header.index <= target_height);
+        if batch.is_empty() {
+            break;
+        }
+        let checked = headers.len();
+        headers.extend(batch);
+        // Later batches answer a locator headed by our last header, so this also
+        // checks they continue it. Checking proof of work is CPU-bound, so it
+        // stays off the runtime's workers.
+        let c = chain.clone();
+        let checking = task::spawn_blocking(move || {
+            let result = c.lock().unwrap().check_headers(&headers, checked);
+            (result, headers)
+        });
+        let (result, checked_headers) = checking.await.ok()?;
+        headers = checked_headers;
+        work = match result {
+            Ok(work) => work,
+            Err(e) => {
+                println!("❌ Rejected headers from {}: {}", peer, e);
+                if let ChainError::InvalidBlock { reason, .. } = &e
+                    && reason.is_peer_fault()
+                {
+                    report_invalid(peer);
+                }
+                return None;
+            }
+        };
+        update(|p| p.headers = headers.len());
+        println!("📜 Downloaded {} headers", headers.len());
+        let last = headers.last().expect("batch was not empty");
+        if !full || last.index >= target_height {
+            break;
+        }
+        locator.insert(0, calculate_hash(last));
+    }
+    if headers.is_empty() {
+        return None;
+    }
+    if work <= chain.lock().unwrap().tip_work() {
+        println!("⚠️ Headers from {} carry no more work than our chain", peer);
+        return None;
+    }
+    Some(headers)
+}
+
+/// Fetches the bodies for `headers` in batches, several at once from
+/// different `sources`, and adds them to the chain in order. A batch that one
+/// peer fails to deliver is retried from the others.
+async fn download_blocks(chain: &Arc<Mutex<Blockchain>>, headers: &[BlockHeader], sources: &[String]) {
+    let batches: Vec<&[BlockHeader]> = headers.chunks(BODY_BATCH).collect();
+    let mut added = 0;
+
+    for window in batches.chunks(sources.len()) {
+        let workers: Vec<_> = window
+            .iter()
+            .enumerate()
+            .map(|(i, batch)| {
+                let expected: Vec<Hash256> = batch.iter().map(calculate_hash).collect();
+                tokio::spawn(fetch_batch(expected, batch[0].index, sources.to_vec(), i))
+            })
+            .collect();
+        let mut fetched = Vec::new();
+        for worker in workers {
+            fetched.push(worker.await.unwrap_or(None));
+        }
+
+        for batch in fetched {
+            let Some((peer, blocks)) = batch else {
+                println!("⚠️ No peer delivered the next blocks, stopping sync");
+                return;
+            };
+            let count = blocks.len();
+            let c = chain.clone();
+            let connecting = peer.clone();
+            if !task::spawn_blocking(move || connect_blocks(&c, blocks, &connecting)).await.unwrap_or(false) {
+                return;
+            }
+            added += count;
+            update(|p| p.blocks = added);
+        }
+        println!("📦 Synced {}/{} blocks", added, headers.len());
+    }
+}
+
+/// Adds blocks synced from `peer` to the chain in order, skipping those we
+/// already have. Returns false once one is rejected, banning `peer` if the
+/// block was its fault. Validation is CPU-bound, so callers on the runtime
+/// run this through `spawn_blocking`.
+fn connect_blocks(chain: &Mutex<Blockchain>, blocks: Vec<Block>, peer: &str) -> bool {
+    let mut c = chain.lock().unwrap();
+    for block in blocks {
+        if c.get_block(&block.hash).is_some() {
+            continue;
+        }
+        let (index, hash) = (block.header.index, block.hash);
+        if let Err(e) = c.add_block(block) {
+            println!("❌ Synced block {} ({}) from {} rejected: {}", index, hash, peer, e);
+            if e.is_peer_fault() {
+                report_invalid(peer);
+            }
+            return false;
+        }
+    }
+    true
+}
+
+/// Fetches the blocks from height `from` whose hashes are `expected`, starting
+/// with the source at `first_source` and moving on to the next on failure.
+/// Blocks must match the downloaded headers exactly. Returns them along with
+/// the peer that sent them.
+async fn fetch_batch(expected: Vec<Hash256>, from: u64, sources: Vec<String>, first_source: usize) -> Option<(String, Vec<Block>)> {
+    let to = from + expected.len() as u64 - 1;
+    for attempt in 0..sources.len() {
+        let peer = &sources[(first_source + attempt) % sources.len()];
+        let Some(blocks) = fetch_blocks(peer, from, to).await else {
+            continue;
+        };
+        if blocks.len() == expected.len() && blocks.iter().zip(&expected).all(|(b, hash)| b.hash == *hash) {
+            return Some((peer.clone(), blocks));
+        }
+        println!("⚠️ Blocks {}..={} from {} do not match their headers", from, to, peer);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::peer_scores;
+    use crate::params::ChainParams;
+
+    fn chain() -> Blockchain {
+        Blockchain::with_params(ChainParams {
+            initial_bits: 0x207fffff,
+            ..ChainParams::default()
+        })
+    }
+
+    #[test]
+    fn only_one_sync_runs_at_a_time() {
+        let guard = SyncGuard::acquire().unwrap();
+        assert!(SyncGuard::acquire().is_none());
+        drop(guard);
+        let panicked = std::panic::catch_unwind(|| {
+            let _guard = SyncGuard::acquire().unwrap();
+            panic!("sync failed");
+        });
+        assert!(panicked.is_err());
+        assert!(SyncGuard::acquire().is_some());
+    }
+
+    #[test]
+    fn connects_blocks_in_order_and_stops_at_a_bad_one() {
+        let mut source = chain();
+        let mut blocks = Vec::new();
+        for _ in 0..3 {
+            let block = source.mine_block("");
+            source.add_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+
+        let peer = "http://sync-test-peer";
+        let target = Mutex::new(chain());
+        assert!(connect_blocks(&target, blocks[..2].to_vec(), peer));
+        // Already known blocks are skipped rather than rejected.
+        assert!(connect_blocks(&target, blocks.clone(), peer));
+        assert_eq!(target.lock().unwrap().tip().hash, blocks[2].hash);
+
+        // Not the peer's fault, so this doesn't touch the peer's score.
+        let mut ahead = source.mine_block("");
+        ahead.header.timestamp += 24 * 60 * 60 * 1000;
+        assert!(!connect_blocks(&target, vec![ahead], peer));
+        assert_eq!(target.lock().unwrap().tip().hash, blocks[2].hash);
+        assert!(!peer_scores().contains_key(peer));
+    }
+}
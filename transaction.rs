@@ -0,0 +1,209 @@
+// === transaction.rs ===
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use crate::hash::Hash256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A payment to a single recipient.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxOutput {
+    /// Hex-encoded ed25519 public key of the recipient.
+    pub to: String,
+    pub amount: u64,
+}
+
+/// A value transfer signed by its sender.
+///
+/// The sender's account is the transaction's single input: it is debited the
+/// sum of all outputs plus the fee.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    /// Hex-encoded ed25519 public key of the sender.
+    pub from: String,
+    pub outputs: Vec<TxOutput>,
+    /// Paid to whoever mines the transaction; higher fees are mined first.
+    pub fee: u64,
+    /// Per-sender sequence number, so a signed transfer can't be replayed.
+    pub nonce: u64,
+    /// Hex-encoded ed25519 signature over `signing_bytes`.
+    pub signature: String,
+}
+
+/// Why a transaction was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    BadPublicKey,
+    BadSignatureEncoding,
+    InvalidSignature,
+    NoOutputs,
+    ZeroAmount,
+    AmountOverflow,
+    MalformedCoinbase,
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::BadPublicKey => write!(f, "sender is not a valid ed25519 public key"),
+            TxError::BadSignatureEncoding => write!(f, "signature is not 64 hex-encoded bytes"),
+            TxError::InvalidSignature => write!(f, "signature does not match sender"),
+            TxError::NoOutputs => write!(f, "transaction has no outputs"),
+            TxError::ZeroAmount => write!(f, "output amount must be positive"),
+            TxError::AmountOverflow => write!(f, "amounts plus fee overflow"),
+            TxError::MalformedCoinbase => write!(f, "coinbase must pay one output, carry the block height as nonce and no fee or signature"),
+        }
+    }
+}
+
+impl std::error::Error for TxError {}
+
+impl Transaction {
+    /// Builds a transaction from `key`'s account and signs it.
+    pub fn new_signed(key: &SigningKey, outputs: Vec<TxOutput>, fee: u64, nonce: u64) -> Self {
+        let mut tx = Transaction {
+            from: hex::encode(key.verifying_key().as_bytes()),
+            outputs,
+            fee,
+            nonce,
+            signature: String::new(),
+        };
+        tx.signature = hex::encode(key.sign(&tx.signing_bytes()).to_bytes());
+        tx
+    }
+
+    /// Builds the coinbase for the block at `height`, minting `amount` to `to`.
+    ///
+    /// A coinbase has no sender; its nonce is the block height so every
+    /// coinbase has a distinct id.
+    pub fn coinbase(height: u64, to: &str, amount: u64) -> Self {
+        Transaction {
+            from: String::new(),
+            outputs: vec![TxOutput { to: to.to_string(), amount }],
+            fee: 0,
+            nonce: height,
+            signature: String::new(),
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.from.is_empty()
+    }
+
+    /// Checks the shape of a coinbase for the block at `height`. Its amount is
+    /// checked against the subsidy and fees by `validation::check_transactions`.
+    pub fn verify_coinbase(&self, height: u64) -> Result<(), TxError> {
+        if !self.is_coinbase() || self.outputs.len() != 1 || self.fee != 0 || self.nonce != height || !self.signature.is_empty() {
+            return Err(TxError::MalformedCoinbase);
+        }
+        Ok(())
+    }
+
+    /// The bytes covered by the signature: everything except the signature itself.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.from, &self.outputs, self.fee, self.nonce)).expect("transaction fields serialize")
+    }
+
+    /// SHA-256 of the full signed transaction.
+    pub fn id(&self) -> Hash256 {
+        Hash256::digest(serde_json::to_vec(self).expect("transaction serializes"))
+    }
+
+    /// Sum of all outputs, or `None` on overflow.
+    pub fn total_out(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |acc, o| acc.checked_add(o.amount))
+    }
+
+    /// Everything debited from the sender (outputs plus fee), or `None` on overflow.
+    pub fn total_cost(&self) -> Option<u64> {
+        self.total_out()?.checked_add(self.fee)
+    }
+
+    /// Returns whether `address` sends or receives value in this transaction.
+    pub fn involves(&self, address: &str) -> bool {
+        self.from == address || self.outputs.iter().any(|o| o.to == address)
+    }
+
+    /// Checks the transaction is well-formed and signed by `from`.
+    pub fn verify(&self) -> Result<(), TxError> {
+        if self.outputs.is_empty() {
+            return Err(TxError::NoOutputs);
+        }
+        if self.outputs.iter().any(|o| o.amount == 0) {
+            return Err(TxError::ZeroAmount);
+        }
+        if self.total_cost().is_none() {
+            return Err(TxError::AmountOverflow);
+        }
+        let key_bytes: [u8; 32] = hex::decode(&self.from)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or(TxError::BadPublicKey)?;
+        let key = VerifyingKey::from_bytes(&key_bytes).map_err(|_| TxError::BadPublicKey)?;
+        let sig_bytes: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or(TxError::BadSignatureEncoding)?;
+        key.verify_strict(&self.signing_bytes(), &Signature::from_bytes(&sig_bytes))
+            .map_err(|_| TxError::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn pay(to: &SigningKey, amount: u64) -> TxOutput {
+        TxOutput { to: hex::encode(to.verifying_key().as_bytes()), amount }
+    }
+
+    #[test]
+    fn signed_transactions_verify() {
+        let tx = Transaction::new_signed(&key(1), vec![pay(&key(2), 5), pay(&key(3), 7)], 0, 0);
+        assert_eq!(tx.verify(), Ok(()));
+        assert_eq!(tx.total_out(), Some(12));
+    }
+
+    #[test]
+    fn tampering_breaks_the_signature() {
+        let mut tx = Transaction::new_signed(&key(1), vec![pay(&key(2), 5)], 0, 0);
+        tx.outputs[0].amount = 500;
+        assert_eq!(tx.verify(), Err(TxError::InvalidSignature));
+
+        let mut tx = Transaction::new_signed(&key(1), vec![pay(&key(2), 5)], 0, 0);
+        tx.nonce = 1;
+        assert_eq!(tx.verify(), Err(TxError::InvalidSignature));
+
+        let mut tx = Transaction::new_signed(&key(1), vec![pay(&key(2), 5)], 0, 0);
+        tx.from = hex::encode(key(3).verifying_key().as_bytes());
+        assert_eq!(tx.verify(), Err(TxError::InvalidSignature));
+    }
+
+    #[test]
+    fn rejects_malformed_transactions() {
+        assert_eq!(Transaction::new_signed(&key(1), vec![], 0, 0).verify(), Err(TxError::NoOutputs));
+        assert_eq!(Transaction::new_signed(&key(1), vec![pay(&key(2), 0)], 0, 0).verify(), Err(TxError::ZeroAmount));
+        assert_eq!(
+            Transaction::new_signed(&key(1), vec![pay(&key(2), u64::MAX), pay(&key(3), 1)], 0, 0).verify(),
+            Err(TxError::AmountOverflow)
+        );
+
+        let mut tx = Transaction::new_signed(&key(1), vec![pay(&key(2), 5)], 0, 0);
+        tx.signature.truncate(10);
+        assert_eq!(tx.verify(), Err(TxError::BadSignatureEncoding));
+        tx.from = "not hex".into();
+        assert_eq!(tx.verify(), Err(TxError::BadPublicKey));
+    }
+
+    #[test]
+    fn ids_cover_the_signature() {
+        let a = Transaction::new_signed(&key(1), vec![pay(&key(2), 5)], 0, 0);
+        let b = Transaction::new_signed(&key(1), vec![pay(&key(2), 5)], 0, 1);
+        assert_ne!(a.id(), b.id());
+        assert_eq!(a.id(), a.clone().id());
+    }
+}
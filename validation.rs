@@ -0,0 +1,517 @@
+// === validation.rs ===
+
+use crate::blockchain::Block;
+use crate::checkpoints::Checkpoints;
+use crate::cryptography::calculate_hash;
+use crate::difficulty::next_bits;
+use crate::encoding::is_supported_version;
+use crate::hash::Hash256;
+use crate::params::ChainParams;
+use crate::state::StateError;
+use crate::transaction::TxError;
+use crate::utils::now_millis;
+use std::fmt;
+
+/// Why a single block was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    AlreadyKnown,
+    UnknownParent,
+    PrevHashMismatch,
+    BadIndex { expected: u64, found: u64 },
+    HashMismatch { computed: Hash256 },
+    BadDifficulty { expected: u32, found: u32 },
+    InvalidPow,
+    UnsupportedVersion(u32),
+    MerkleRootMismatch,
+    TimestampBeforeParent { parent: u128, found: u128 },
+    /// Not later than the median timestamp of the blocks before it.
+    TimestampTooOld { median_time_past: u128, found: u128 },
+    /// Further ahead of the local clock than `max_future_drift_ms` allows.
+    TimestampTooFarAhead { limit: u128, found: u128 },
+    InvalidTransaction { position: usize, reason: TxError },
+    InvalidState { position: usize, reason: StateError },
+    MissingCoinbase,
+    ExtraCoinbase { position: usize },
+    CoinbaseTooLarge { claimed: u64, allowed: u64 },
+    /// A checkpoint names a different block at this height.
+    CheckpointMismatch { expected: Hash256 },
+    /// The block forks off the canonical chain at or below a checkpoint we've passed.
+    ForkBelowCheckpoint { checkpoint: u64 },
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::AlreadyKnown => write!(f, "block already known"),
+            BlockError::UnknownParent => write!(f, "parent block unknown"),
+            BlockError::PrevHashMismatch => write!(f, "prev_hash mismatch"),
+            BlockError::BadIndex { expected, found } => write!(f, "index {} does not follow parent, expected {}", found, expected),
+            BlockError::HashMismatch { computed } => write!(f, "stored hash does not match contents ({})", computed),
+            BlockError::BadDifficulty { expected, found } => write!(f, "unexpected difficulty {:08x}, expected {:08x}", found, expected),
+            BlockError::InvalidPow => write!(f, "PoW invalid"),
+            BlockError::UnsupportedVersion(v) => write!(f, "unsupported header version {}", v),
+            BlockError::MerkleRootMismatch => write!(f, "merkle root does not match transactions"),
+            BlockError::TimestampBeforeParent { parent, found } => write!(f, "timestamp {} precedes parent timestamp {}", found, parent),
+            BlockError::TimestampTooOld { median_time_past, found } => {
+                write!(f, "timestamp {} is not after median time past {}", found, median_time_past)
+            }
+            BlockError::TimestampTooFarAhead { limit, found } => write!(f, "timestamp {} is beyond the allowed {}", found, limit),
+            BlockError::InvalidTransaction { position, reason } => write!(f, "transaction {}: {}", position, reason),
+            BlockError::InvalidState { position, reason } => write!(f, "transaction {}: {}", position, reason),
+            BlockError::MissingCoinbase => write!(f, "first transaction must be a coinbase"),
+            BlockError::ExtraCoinbase { position } => write!(f, "transaction {} is a second coinbase", position),
+            BlockError::CoinbaseTooLarge { claimed, allowed } => write!(f, "coinbase claims {}, at most {} allowed", claimed, allowed),
+            BlockError::CheckpointMismatch { expected } => write!(f, "conflicts with checkpoint {}", expected),
+            BlockError::ForkBelowCheckpoint { checkpoint } => write!(f, "forks below checkpoint at height {}", checkpoint),
+        }
+    }
+}
+
+impl BlockError {
+    /// Whether whoever sent the block is to blame. A duplicate, a missing
+    /// parent or a timestamp ahead of our own clock can happen to honest peers.
+    pub fn is_peer_fault(&self) -> bool {
+        !matches!(
+            self,
+            BlockError::AlreadyKnown | BlockError::UnknownParent | BlockError::TimestampTooFarAhead { .. }
+        )
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Why a whole chain was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    Empty,
+    /// The first block that failed, with the reason.
+    InvalidBlock { index: u64, hash: Hash256, reason: BlockError },
+    /// The chain was built on another network's genesis block.
+    WrongGenesis { expected: Hash256, found: Hash256 },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Empty => write!(f, "chain has no blocks"),
+            ChainError::InvalidBlock { index, hash, reason } => write!(f, "block {} ({}): {}", index, hash, reason),
+            ChainError::WrongGenesis { expected, found } => write!(f, "genesis {} does not match ours ({})", found, expected),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// Checks that `block` uses a known header version and carries the hash of its own contents.
+pub fn check_hash(block: &Block) -> Result<(), BlockError> {
+    if !is_supported_version(block.header.version) {
+        return Err(BlockError::UnsupportedVersion(block.header.version));
+    }
+    let computed = calculate_hash(&block.header);
+    if computed != block.hash {
+        return Err(BlockError::HashMismatch { computed });
+    }
+    Ok(())
+}
+
+/// Checks every transaction in `block` is well-formed, correctly signed and
+/// committed to by the header's merkle root.
+///
+/// Every block but genesis opens with a coinbase, which may claim no more than
+/// the block subsidy plus the fees of the other transactions. Genesis holds
+/// only coinbases, one per allocation, and is not limited by the subsidy.
+pub fn check_transactions(block: &Block, params: &ChainParams) -> Result<(), BlockError> {
+    if Block::compute_merkle_root(&block.transactions) != block.header.merkle_root {
+        return Err(BlockError::MerkleRootMismatch);
+    }
+    let height = block.header.index;
+    if height == 0 {
+        for (position, tx) in block.transactions.iter().enumerate() {
+            tx.verify_coinbase(0).map_err(|reason| BlockError::InvalidTransaction { position, reason })?;
+        }
+        return Ok(());
+    }
+    let mut fees: u64 = 0;
+    for (position, tx) in block.transactions.iter().enumerate() {
+        if tx.is_coinbase() {
+            if position > 0 {
+                return Err(BlockError::ExtraCoinbase { position });
+            }
+            tx.verify_coinbase(height).map_err(|reason| BlockError::InvalidTransaction { position, reason })?;
+            continue;
+        }
+        if position == 0 {
+            return Err(BlockError::MissingCoinbase);
+        }
+        tx.verify().map_err(|reason| BlockError::InvalidTransaction { position, reason })?;
+        fees = fees.saturating_add(tx.fee);
+    }
+
+    let Some(coinbase) = block.transactions.first().filter(|tx| tx.is_coinbase()) else {
+        return Err(BlockError::MissingCoinbase);
+    };
+    let claimed = coinbase.total_out().unwrap_or(u64::MAX);
+    let allowed = params.block_subsidy(height).saturating_add(fees);
+    if claimed > allowed {
+        return Err(BlockError::CoinbaseTooLarge { claimed, allowed });
+    }
+    Ok(())
+}
+
+/// Checks that `block` follows directly on from `parent`.
+pub fn check_link(block: &Block, parent: &Block) -> Result<(), BlockError> {
+    if block.header.prev_hash != parent.hash {
+        return Err(BlockError::PrevHashMismatch);
+    }
+    if block.header.index != parent.header.index + 1 {
+        return Err(BlockError::BadIndex { expected: parent.header.index + 1, found: block.header.index });
+    }
+    Ok(())
+}
+
+/// Checks only that `block`'s hash covers its header and its header commits
+/// to its transactions.
+pub fn check_committed(block: &Block) -> Result<(), BlockError> {
+    check_hash(block)?;
+    if Block::compute_merkle_root(&block.transactions) != block.header.merkle_root {
+        return Err(BlockError::MerkleRootMismatch);
+    }
+    Ok(())
+}
+
+/// Median timestamp of `parent` and up to `span - 1` of its ancestors.
+///
+/// `ancestor` resolves a height on `parent`'s branch; heights it can't
+/// resolve, e.g. because they were pruned, are left out of the median.
+pub fn median_time_past<'a>(span: usize, parent: &'a Block, ancestor: impl Fn(u64) -> Option<&'a Block>) -> u128 {
+    let lowest = parent.header.index.saturating_sub(span.saturating_sub(1) as u64);
+    let mut times: Vec<u128> = (lowest..parent.header.index)
+        .filter_map(|height| ancestor(height).filter(|b| b.header.index == height))
+        .map(|b| b.header.timestamp)
+        .collect();
+    times.push(parent.header.timestamp);
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+/// Checks that `block` is later than `median_time_past` and not too far ahead
+/// of the local clock.
+pub fn check_timestamp(block: &Block, median_time_past: u128, params: &ChainParams) -> Result<(), BlockError> {
+    let found = block.header.timestamp;
+    if found <= median_time_past {
+        return Err(BlockError::TimestampTooOld { median_time_past, found });
+    }
+    let limit = now_millis() + params.max_future_drift_ms;
+    if found > limit {
+        return Err(BlockError::TimestampTooFarAhead { limit, found });
+    }
+    Ok(())
+}
+
+/// Checks `block`'s header against its parent and the difficulty and median
+/// time past the chain expects of it, leaving the transactions alone. This is
+/// all that can be checked of a header downloaded without its body. A block
+/// may not predate its parent even when it is later than the median time past.
+pub fn check_header(
+    block: &Block,
+    parent: &Block,
+    expected_bits: u32,
+    median_time_past: u128,
+    params: &ChainParams,
+) -> Result<(), BlockError> {
+    check_link(block, parent)?;
+    if block.header.timestamp < parent.header.timestamp {
+        return Err(BlockError::TimestampBeforeParent { parent: parent.header.timestamp, found: block.header.timestamp });
+    }
+    check_timestamp(block, median_time_past, params)?;
+    check_hash(block)?;
+    if block.header.bits != expected_bits {
+        return Err(BlockError::BadDifficulty { expected: expected_bits, found: block.header.bits });
+    }
+    if !params.pow.verify(&block.header) {
+        return Err(BlockError::InvalidPow);
+    }
+    Ok(())
+}
+
+/// Checks `block` against its parent and the difficulty and median time past
+/// the chain expects of it.
+pub fn check_block(
+    block: &Block,
+    parent: &Block,
+    expected_bits: u32,
+    median_time_past: u128,
+    params: &ChainParams,
+) -> Result<(), BlockError> {
+    check_header(block, parent, expected_bits, median_time_past, params)?;
+    check_transactions(block, params)
+}
+
+/// Validates a contiguous run of blocks, such as `storage::load_chain` output
+/// or a chain received from a peer.
+///
+/// The first block is only checked against its own hash (and PoW unless it is
+/// genesis), since its parent may have been pruned; every later block gets the
+/// full `check_block` treatment.
+///
+/// Blocks up to the highest checkpoint in the run are pinned by that
+/// checkpoint through their hash links, so they only get `check_committed`:
+/// signatures, proof of work, difficulty and timestamps aren't re-checked.
+pub fn validate_blocks(blocks: &[Block], params: &ChainParams, checkpoints: &Checkpoints) -> Result<(), ChainError> {
+    let invalid = |block: &Block, reason: BlockError| ChainError::InvalidBlock {
+        index: block.header.index,
+        hash: block.hash,
+        reason,
+    };
+
+    let first = blocks.first().ok_or(ChainError::Empty)?;
+    for block in blocks {
+        checkpoints.check(block.header.index, &block.hash).map_err(|e| invalid(block, e))?;
+    }
+    let trusted_until = blocks.iter().rev().map(|b| b.header.index).find(|height| checkpoints.get(*height).is_some());
+    let trusted = |block: &Block| trusted_until.is_some_and(|height| block.header.index <= height);
+
+    if trusted(first) {
+        check_committed(first).map_err(|e| invalid(first, e))?;
+    } else {
+        check_hash(first).map_err(|e| invalid(first, e))?;
+        if first.header.index > 0 && !params.pow.verify(&first.header) {
+            return Err(invalid(first, BlockError::InvalidPow));
+        }
+        check_transactions(first, params).map_err(|e| invalid(first, e))?;
+    }
+
+    for (pos, pair) in blocks.windows(2).enumerate() {
+        let (parent, block) = (&pair[0], &pair[1]);
+        if trusted(block) {
+            check_link(block, parent).and_then(|_| check_committed(block)).map_err(|e| invalid(block, e))?;
+            continue;
+        }
+        // Earlier links already held, so heights map straight onto positions.
+        let ancestor = |height: u64| blocks[..=pos].get(height.saturating_sub(first.header.index) as usize);
+        let expected_bits = next_bits(params, parent, ancestor);
+        let mtp = median_time_past(params.median_time_span, parent, ancestor);
+        check_block(block, parent, expected_bits, mtp, params).map_err(|e| invalid(block, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::cryptography::verify_pow;
+    use crate::transaction::{Transaction, TxOutput};
+    use ed25519_dalek::SigningKey;
+
+    fn params() -> ChainParams {
+        ChainParams {
+            initial_bits: 0x207fffff,
+            target_block_time_ms: 60_000,
+            retarget_interval: 1_000,
+            ..ChainParams::default()
+        }
+    }
+
+    /// A valid child of `parent` with the given timestamp.
+    fn child(parent: &Block, timestamp: u128) -> Block {
+        child_with(parent, timestamp, Vec::new())
+    }
+
+    /// Like `child`, with an empty coinbase followed by `transactions`.
+    fn child_with(parent: &Block, timestamp: u128, transactions: Vec<Transaction>) -> Block {
+        let mut transactions = transactions;
+        transactions.insert(0, Transaction::coinbase(parent.header.index + 1, "", 0));
+        child_exact(parent, timestamp, transactions)
+    }
+
+    /// A child of `parent` carrying exactly `transactions`, coinbase or not.
+    fn child_exact(parent: &Block, timestamp: u128, transactions: Vec<Transaction>) -> Block {
+        (0..)
+            .map(|nonce| Block::new(parent.header.index + 1, timestamp, parent.hash, transactions.clone(), parent.header.bits, nonce))
+            .find(|b| verify_pow(&b.hash, b.header.bits))
+            .unwrap()
+    }
+
+    fn valid_blocks(count: usize) -> Vec<Block> {
+        let mut blocks = Blockchain::with_params(params()).blocks;
+        for i in 1..count {
+            let next = child(&blocks[i - 1], i as u128);
+            blocks.push(next);
+        }
+        blocks
+    }
+
+    #[test]
+    fn accepts_a_valid_chain() {
+        assert_eq!(validate_blocks(&valid_blocks(5), &params(), &Checkpoints::default()), Ok(()));
+    }
+
+    #[test]
+    fn names_the_first_tampered_block() {
+        let mut blocks = valid_blocks(5);
+        blocks[2].header.nonce += 1;
+        blocks[3].header.nonce += 1;
+        let Err(ChainError::InvalidBlock { index, reason, .. }) = validate_blocks(&blocks, &params(), &Checkpoints::default()) else {
+            panic!("tampered chain validated");
+        };
+        assert_eq!(index, 2);
+        assert!(matches!(reason, BlockError::HashMismatch { .. }));
+    }
+
+    #[test]
+    fn rejects_unknown_header_versions() {
+        let mut blocks = valid_blocks(2);
+        blocks[1].header.version += 1;
+        blocks[1].hash = calculate_hash(&blocks[1].header);
+        let version = blocks[1].header.version;
+        assert_eq!(check_hash(&blocks[1]), Err(BlockError::UnsupportedVersion(version)));
+    }
+
+    #[test]
+    fn rejects_broken_links() {
+        let mut blocks = valid_blocks(4);
+        blocks.remove(2);
+        let Err(ChainError::InvalidBlock { index, reason, .. }) = validate_blocks(&blocks, &params(), &Checkpoints::default()) else {
+            panic!("gapped chain validated");
+        };
+        assert_eq!((index, reason), (3, BlockError::PrevHashMismatch));
+    }
+
+    #[test]
+    fn rejects_timestamps_not_after_the_median() {
+        let blocks = valid_blocks(3);
+        // Not before the parent, but no later than a median the caller says is ahead of it.
+        let mtp = blocks[2].header.timestamp + 1;
+        let early = child(&blocks[2], mtp);
+        assert_eq!(
+            check_block(&early, &blocks[2], early.header.bits, mtp, &params()),
+            Err(BlockError::TimestampTooOld { median_time_past: mtp, found: mtp })
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_before_the_parent_even_after_the_median() {
+        let blocks = valid_blocks(5);
+        let mtp = median_time_past(params().median_time_span, &blocks[4], |height| blocks.get(height as usize));
+        let early = child(&blocks[4], blocks[4].header.timestamp - 1);
+        assert!(early.header.timestamp > mtp);
+        assert_eq!(
+            check_block(&early, &blocks[4], early.header.bits, mtp, &params()),
+            Err(BlockError::TimestampBeforeParent { parent: blocks[4].header.timestamp, found: early.header.timestamp })
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_too_far_ahead() {
+        let blocks = valid_blocks(2);
+        let limit = now_millis() + params().max_future_drift_ms;
+        let ahead = child(&blocks[1], limit + 60_000);
+        assert!(matches!(
+            check_block(&ahead, &blocks[1], ahead.header.bits, 0, &params()),
+            Err(BlockError::TimestampTooFarAhead { found, .. }) if found == ahead.header.timestamp
+        ));
+        let close = child(&blocks[1], now_millis() + 60_000);
+        assert_eq!(check_block(&close, &blocks[1], close.header.bits, 0, &params()), Ok(()));
+    }
+
+    #[test]
+    fn median_skips_unknown_ancestors() {
+        let blocks = valid_blocks(6);
+        let median = |known: &[Block]| median_time_past(3, &blocks[5], |height| known.iter().find(|b| b.header.index == height));
+        assert_eq!(median(&blocks), 4);
+        // With heights 3 and 4 pruned, only the parent is left.
+        assert_eq!(median(&blocks[..3]), 5);
+    }
+
+    #[test]
+    fn rejects_forged_transactions() {
+        let blocks = valid_blocks(2);
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let to = hex::encode(SigningKey::from_bytes(&[2; 32]).verifying_key().as_bytes());
+        let mut forged = Transaction::new_signed(&key, vec![TxOutput { to, amount: 5 }], 0, 0);
+        forged.outputs[0].amount = 5_000;
+        let block = child_with(&blocks[1], 2, vec![forged]);
+        assert_eq!(
+            check_block(&block, &blocks[1], block.header.bits, 0, &params()),
+            Err(BlockError::InvalidTransaction { position: 1, reason: TxError::InvalidSignature })
+        );
+    }
+
+    #[test]
+    fn rejects_bodies_the_header_does_not_commit_to() {
+        let blocks = valid_blocks(2);
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let to = hex::encode(SigningKey::from_bytes(&[2; 32]).verifying_key().as_bytes());
+        let mut block = child(&blocks[1], 2);
+        block.transactions.push(Transaction::new_signed(&key, vec![TxOutput { to, amount: 5 }], 0, 0));
+        assert_eq!(check_block(&block, &blocks[1], block.header.bits, 0, &params()), Err(BlockError::MerkleRootMismatch));
+    }
+
+    fn paid_transfer(fee: u64) -> Transaction {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let to = hex::encode(SigningKey::from_bytes(&[2; 32]).verifying_key().as_bytes());
+        Transaction::new_signed(&key, vec![TxOutput { to, amount: 5 }], fee, 0)
+    }
+
+    #[test]
+    fn coinbase_may_claim_the_subsidy_plus_fees() {
+        let blocks = valid_blocks(2);
+        let allowed = params().block_subsidy(2) + 7;
+        let block = child_exact(&blocks[1], 2, vec![Transaction::coinbase(2, "miner", allowed), paid_transfer(7)]);
+        assert_eq!(check_block(&block, &blocks[1], block.header.bits, 0, &params()), Ok(()));
+
+        let greedy = child_exact(&blocks[1], 2, vec![Transaction::coinbase(2, "miner", allowed + 1), paid_transfer(7)]);
+        assert_eq!(
+            check_block(&greedy, &blocks[1], greedy.header.bits, 0, &params()),
+            Err(BlockError::CoinbaseTooLarge { claimed: allowed + 1, allowed })
+        );
+    }
+
+    #[test]
+    fn blocks_need_exactly_one_leading_coinbase() {
+        let blocks = valid_blocks(2);
+        let check = |transactions| {
+            let block = child_exact(&blocks[1], 2, transactions);
+            check_block(&block, &blocks[1], block.header.bits, 0, &params())
+        };
+        assert_eq!(check(Vec::new()), Err(BlockError::MissingCoinbase));
+        assert_eq!(check(vec![paid_transfer(0)]), Err(BlockError::MissingCoinbase));
+        assert_eq!(
+            check(vec![Transaction::coinbase(2, "a", 0), Transaction::coinbase(2, "b", 0)]),
+            Err(BlockError::ExtraCoinbase { position: 1 })
+        );
+        assert_eq!(
+            check(vec![Transaction::coinbase(1, "miner", 0)]),
+            Err(BlockError::InvalidTransaction { position: 0, reason: TxError::MalformedCoinbase })
+        );
+    }
+
+    #[test]
+    fn checkpointed_blocks_must_match_but_skip_full_checks() {
+        let mut blocks = valid_blocks(3);
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let to = hex::encode(SigningKey::from_bytes(&[2; 32]).verifying_key().as_bytes());
+        let mut forged = Transaction::new_signed(&key, vec![TxOutput { to, amount: 5 }], 0, 0);
+        forged.outputs[0].amount = 5_000;
+        blocks[2] = child_with(&blocks[1], 2, vec![forged]);
+        assert!(validate_blocks(&blocks, &params(), &Checkpoints::default()).is_err());
+
+        let pinned = Checkpoints::new([(2, blocks[2].hash)].into());
+        assert_eq!(validate_blocks(&blocks, &params(), &pinned), Ok(()));
+
+        let other = Hash256::digest("other");
+        let Err(ChainError::InvalidBlock { index, reason, .. }) =
+            validate_blocks(&blocks, &params(), &Checkpoints::new([(1, other)].into()))
+        else {
+            panic!("expected a checkpoint mismatch");
+        };
+        assert_eq!((index, reason), (1, BlockError::CheckpointMismatch { expected: other }));
+    }
+
+    #[test]
+    fn rejects_an_empty_chain() {
+        assert_eq!(validate_blocks(&[], &params(), &Checkpoints::default()), Err(ChainError::Empty));
+    }
+}